@@ -0,0 +1,463 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use room::graphics::{Palette, Picture};
+use room::map::nodebuilder::NodeBuilder;
+use room::map::lint::lint as lint_map;
+use room::map::overview::Overview;
+use room::map::{Map, MapLumps};
+use room::music::{is_mus, mus_to_midi};
+use room::png::encode_rgba;
+use room::resource::{open_container, Namespace, ResourceStack};
+use room::sound::Sound;
+use room::wad::{load_wad_file, Identification, Wad, WadWriter};
+
+const USAGE: &str = "\
+Usage: room-wad <command> [arguments]
+
+Commands:
+  info <wad>                                   header and contents summary
+  list <wad> [--namespace <ns>] [pattern]      name, offset and size of lumps
+  extract <wad> [-o <dir>] [--convert] [--iwad <iwad>] [pattern...]
+                                               write lumps to files
+  merge -o <out.wad> <wad> <pwad|pk3|dir>...   combine WADs into one PWAD
+  diff <wad> <wad>                             compare two WADs lump by lump
+  nodes <wad> -o <out.wad> [map...]            rebuild the BSP nodes of maps
+  lint <wad> [--iwad <iwad>] [map...]          check maps for problems
+  render <wad> <map> -o <file.svg|file.png> [--size <pixels>] [--things] [--tags]
+                                               draw a map from above
+
+Patterns are globs on lump names (* and ?), namespaces are global, sprites,
+flats or patches. --convert writes sounds as WAV, music as MIDI and pictures
+as PNG, with the palette of the WAD or of the IWAD given with --iwad. nodes
+and lint work on all maps unless some are named; lint looks up textures and
+flats in the IWAD given with --iwad as well.";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let result = match args.first().map(String::as_str) {
+        Some("info") => info(&args[1..]),
+        Some("list") => list(&args[1..]),
+        Some("extract") => extract(&args[1..]),
+        Some("merge") => merge(&args[1..]),
+        Some("diff") => diff(&args[1..]),
+        Some("nodes") => nodes(&args[1..]),
+        Some("lint") => lint(&args[1..]),
+        Some("render") => render(&args[1..]),
+        _ => Err(USAGE.to_string()),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn open(path: &str) -> Result<Wad, String> {
+    load_wad_file(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+/**
+ * Splits arguments into positional ones and the values of the given options. Options listed
+ * in `flags` take no value.
+ */
+fn parse_args<'a>(args: &'a [String], options: &[&str], flags: &[&str])
+    -> Result<(Vec<&'a str>, HashMap<&'a str, &'a str>), String> {
+    let mut positional = Vec::new();
+    let mut values = HashMap::new();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        if flags.contains(&arg.as_str()) {
+            values.insert(arg.as_str(), "");
+        } else if options.contains(&arg.as_str()) {
+            let value = iter.next().ok_or(format!("{} needs a value", arg))?;
+            values.insert(arg.as_str(), value.as_str());
+        } else if arg.starts_with('-') && arg.len() > 1 {
+            return Err(format!("unknown option {}\n\n{}", arg, USAGE));
+        } else {
+            positional.push(arg.as_str());
+        }
+    }
+
+    Ok((positional, values))
+}
+
+fn info(args: &[String]) -> Result<ExitCode, String> {
+    let (positional, _) = parse_args(args, &[], &[])?;
+    let [path] = positional[..] else { return Err(USAGE.to_string()) };
+    let wad = open(path)?;
+    let identification = wad.identification();
+    let (numlumps, infotablesofs) = (wad.header().numlumps, wad.header().infotablesofs);
+    let maps: Vec<String> = map_markers(&wad).into_iter()
+        .map(|index| wad.lump(index).unwrap().name.to_string())
+        .collect();
+    let stack = ResourceStack::new(wad);
+
+    println!("File:       {}", path);
+    println!("Type:       {}", identification);
+    println!("Game:       {} ({:?}, {:?})", stack.game(), stack.game().mode, stack.game().mission);
+    println!("Lumps:      {}", numlumps);
+    println!("Directory:  {}", infotablesofs);
+    for namespace in [Namespace::Sprites, Namespace::Flats, Namespace::Patches] {
+        println!("{:<11} {}", format!("{:?}:", namespace), stack.namespace(namespace).len());
+    }
+    println!("Maps:       {}", maps.join(" "));
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/**
+ * The indices of the map marker lumps of a WAD.
+ */
+fn map_markers(wad: &Wad) -> Vec<usize> {
+    wad.iter().enumerate()
+        .filter(|(index, dir)| dir.size == 0
+            && wad.lump(index + 1).is_some_and(|next| next.name == "THINGS" || next.name == "TEXTMAP"))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+fn parse_namespace(name: &str) -> Result<Option<Namespace>, String> {
+    match name.to_ascii_lowercase().as_str() {
+        "global" => Ok(None),
+        "sprites" => Ok(Some(Namespace::Sprites)),
+        "flats" => Ok(Some(Namespace::Flats)),
+        "patches" => Ok(Some(Namespace::Patches)),
+        _ => Err(format!("unknown namespace {}", name)),
+    }
+}
+
+fn list(args: &[String]) -> Result<ExitCode, String> {
+    let (positional, options) = parse_args(args, &["--namespace"], &[])?;
+    let (path, pattern) = match positional[..] {
+        [path] => (path, "*"),
+        [path, pattern] => (path, pattern),
+        _ => return Err(USAGE.to_string()),
+    };
+    let namespace = options.get("--namespace").map(|ns| parse_namespace(ns)).transpose()?;
+    let wad = open(path)?;
+    let entries: Vec<_> = wad.iter().map(|dir| (dir.name, dir.filepos, dir.size)).collect();
+    let stack = ResourceStack::new(wad);
+
+    // with a single WAD on the stack, lump numbers are directory indices
+    println!("{:>5}  {:<8}  {:>10}  {:>10}", "#", "NAME", "OFFSET", "SIZE");
+    for (num, (name, filepos, size)) in entries.into_iter().enumerate() {
+        if namespace.is_some_and(|ns| stack.lump_namespace(num) != ns) || !glob_match(pattern, &name.to_string()) {
+            continue;
+        }
+        println!("{:>5}  {:<8}  {:>10}  {:>10}", num, name, filepos, size);
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn extract(args: &[String]) -> Result<ExitCode, String> {
+    let (positional, options) = parse_args(args, &["-o", "--iwad"], &["--convert"])?;
+    let Some((&path, patterns)) = positional.split_first() else { return Err(USAGE.to_string()) };
+    let out_dir = PathBuf::from(options.get("-o").copied().unwrap_or("."));
+    let convert = options.contains_key("--convert");
+    let wad = open(path)?;
+
+    let palette_source = ResourceStack::new(open(options.get("--iwad").copied().unwrap_or(path))?);
+    let palette = Palette::load(&palette_source).ok().map(|mut palettes| palettes.swap_remove(Palette::NORMAL));
+
+    fs::create_dir_all(&out_dir).map_err(|e| format!("{}: {}", out_dir.display(), e))?;
+
+    let mut written: HashMap<String, usize> = HashMap::new();
+    for (index, dir) in wad.iter().enumerate() {
+        let name = dir.name.to_string();
+        if dir.size == 0 || !(patterns.is_empty() || patterns.iter().any(|p| glob_match(p, &name))) {
+            continue;
+        }
+
+        let data = wad.lump_data(dir);
+        let (bytes, extension) = if convert { convert_lump(data, palette.as_ref()) } else { (data.to_vec(), "lmp") };
+
+        // map lumps such as THINGS appear once per map
+        let count = written.entry(name.clone()).or_default();
+        let file_name = match *count {
+            0 => format!("{}.{}", name, extension),
+            _ => format!("{}-{}.{}", name, index, extension),
+        };
+        *count += 1;
+
+        let file = out_dir.join(file_name);
+        fs::write(&file, bytes).map_err(|e| format!("{}: {}", file.display(), e))?;
+        println!("{}", file.display());
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/**
+ * Converts a lump to a common file format where Room has a decoder for it, otherwise returns
+ * the raw lump.
+ */
+fn convert_lump(data: &[u8], palette: Option<&Palette>) -> (Vec<u8>, &'static str) {
+    if is_mus(data) {
+        if let Ok(midi) = mus_to_midi(data) {
+            return (midi, "mid");
+        }
+    }
+    if let Some(sound) = Sound::from_lump(data) {
+        return (sound.to_wav(), "wav");
+    }
+    if let Some(palette) = palette.filter(|_| Picture::is_picture(data)) {
+        if let Ok(picture) = Picture::from_bytes(data) {
+            return (encode_rgba(picture.width as u32, picture.height as u32, &picture.to_rgba(palette)), "png");
+        }
+    }
+    (data.to_vec(), "lmp")
+}
+
+fn merge(args: &[String]) -> Result<ExitCode, String> {
+    let (positional, options) = parse_args(args, &["-o"], &[])?;
+    let out = options.get("-o").ok_or(USAGE.to_string())?;
+    let Some((first, rest)) = positional.split_first() else { return Err(USAGE.to_string()) };
+
+    let mut stack = ResourceStack::new(open(first)?);
+    for path in rest {
+        let container = open_container(path).map_err(|e| format!("{}: {}", path, e))?;
+        stack.add_boxed(container).map_err(|e| format!("{}: {}", path, e))?;
+    }
+
+    // global lumps keep their order, so map lumps stay behind their markers
+    let mut writer = WadWriter::new(Identification::PWAD);
+    for num in 0..stack.len() {
+        if stack.lump_namespace(num).is_none() && !stack.is_marker(num) {
+            add_stack_lump(&mut writer, &stack, num);
+        }
+    }
+
+    for (namespace, start, end) in [
+        (Namespace::Sprites, "S_START", "S_END"),
+        (Namespace::Flats, "F_START", "F_END"),
+        (Namespace::Patches, "P_START", "P_END"),
+    ] {
+        if stack.namespace(namespace).is_empty() {
+            continue;
+        }
+        writer.add_marker(start);
+        for &num in stack.namespace(namespace) {
+            add_stack_lump(&mut writer, &stack, num);
+        }
+        writer.add_marker(end);
+    }
+
+    writer.save(out).map_err(|e| format!("{}: {}", out, e))?;
+    println!("{}: {} lumps", out, writer.len());
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn add_stack_lump(writer: &mut WadWriter, stack: &ResourceStack, num: usize) {
+    writer.add_lump(stack.lump_name(num).unwrap(), stack.lump_data(num).unwrap());
+}
+
+fn diff(args: &[String]) -> Result<ExitCode, String> {
+    let (positional, _) = parse_args(args, &[], &[])?;
+    let [left_path, right_path] = positional[..] else { return Err(USAGE.to_string()) };
+    let left = open(left_path)?;
+    let right = open(right_path)?;
+
+    // lumps are paired by name and by how often the name occurred before, so the THINGS of
+    // the second map are compared with the THINGS of the second map
+    let keyed = |wad: &Wad| -> Vec<(String, usize)> {
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        wad.iter().map(|dir| {
+            let name = dir.name.to_string();
+            let count = seen.entry(name.clone()).or_default();
+            *count += 1;
+            (name, *count)
+        }).collect()
+    };
+    let left_keys = keyed(&left);
+    let right_keys = keyed(&right);
+    let right_index: HashMap<&(String, usize), usize> = right_keys.iter().enumerate().map(|(i, k)| (k, i)).collect();
+    let left_index: HashMap<&(String, usize), usize> = left_keys.iter().enumerate().map(|(i, k)| (k, i)).collect();
+
+    let mut differences = 0;
+    for (index, key) in left_keys.iter().enumerate() {
+        let left_dir = left.lump(index).unwrap();
+        match right_index.get(key) {
+            None => {
+                println!("- {:<8} {:>10}", key.0, left_dir.size);
+                differences += 1;
+            }
+            Some(&other) => {
+                let right_dir = right.lump(other).unwrap();
+                if left.lump_data(left_dir) != right.lump_data(right_dir) {
+                    println!("~ {:<8} {:>10} -> {}", key.0, left_dir.size, right_dir.size);
+                    differences += 1;
+                }
+            }
+        }
+    }
+    for (index, key) in right_keys.iter().enumerate() {
+        if !left_index.contains_key(key) {
+            println!("+ {:<8} {:>10}", key.0, right.lump(index).unwrap().size);
+            differences += 1;
+        }
+    }
+
+    if differences == 0 {
+        println!("{} and {} have identical lumps", left_path, right_path);
+        return Ok(ExitCode::SUCCESS);
+    }
+    Ok(ExitCode::from(1))
+}
+
+// The lumps of a Doom format map, in the order vanilla expects them
+const VANILLA_MAP_LUMPS: [&str; 10] = [
+    "THINGS", "LINEDEFS", "SIDEDEFS", "VERTEXES", "SEGS", "SSECTORS", "NODES", "SECTORS", "REJECT", "BLOCKMAP",
+];
+
+fn nodes(args: &[String]) -> Result<ExitCode, String> {
+    let (positional, options) = parse_args(args, &["-o"], &[])?;
+    let out = options.get("-o").ok_or(USAGE.to_string())?;
+    let Some((path, selected)) = positional.split_first() else { return Err(USAGE.to_string()) };
+    let wad = open(path)?;
+
+    let mut writer = WadWriter::new(wad.identification());
+    let mut index = 0;
+    while let Some(dir) = wad.lump(index) {
+        let is_map = wad.lump(index + 1).is_some_and(|next| next.name == "THINGS");
+        let wanted = selected.is_empty() || selected.iter().any(|name| dir.name == name.to_uppercase().as_str());
+        index += 1;
+
+        if !is_map || !wanted {
+            writer.add_lump(dir.name, wad.lump_data(dir));
+            continue;
+        }
+
+        let lumps = MapLumps::at(&wad, index - 1);
+        let map = Map::from_lumps(&lumps).map_err(|e| format!("{}: {}", dir.name, e))?;
+        let bsp = NodeBuilder::default().build(&map).map_err(|e| format!("{}: {}", dir.name, e))?;
+        let built = bsp.to_lumps().map_err(|e| format!("{}: {}", dir.name, e))?;
+        println!("{}: {} nodes, {} segs, {} subsectors", dir.name, bsp.nodes.len(), bsp.segs.len(), bsp.subsectors.len());
+
+        // the rebuilt lumps go where vanilla tools put them, other map lumps follow
+        writer.add_marker(dir.name);
+        for name in VANILLA_MAP_LUMPS {
+            let data = match name {
+                "VERTEXES" => Some(built.vertexes.as_slice()),
+                "SEGS" => Some(built.segs.as_slice()),
+                "SSECTORS" => Some(built.ssectors.as_slice()),
+                "NODES" => Some(built.nodes.as_slice()),
+                _ => lumps.get(name),
+            };
+            if let Some(data) = data {
+                writer.add_lump(name, data);
+            }
+        }
+        for (name, data) in lumps.iter().filter(|(name, _)| !VANILLA_MAP_LUMPS.iter().any(|vanilla| name == vanilla)) {
+            writer.add_lump(name, data);
+        }
+        index += lumps.iter().count();
+    }
+
+    writer.save(out).map_err(|e| format!("{}: {}", out, e))?;
+    println!("{}: {} lumps", out, writer.len());
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn lint(args: &[String]) -> Result<ExitCode, String> {
+    let (positional, options) = parse_args(args, &["--iwad"], &[])?;
+    let Some((path, selected)) = positional.split_first() else { return Err(USAGE.to_string()) };
+    let wad = open(path)?;
+
+    let resources = match options.get("--iwad") {
+        Some(iwad) => {
+            let mut stack = ResourceStack::new(open(iwad)?);
+            stack.add_pwad(open(path)?).map_err(|e| format!("{}: {}", path, e))?;
+            stack
+        }
+        None => ResourceStack::new(open(path)?),
+    };
+
+    let mut problems = 0;
+    for index in map_markers(&wad) {
+        let name = wad.lump(index).unwrap().name;
+        if !selected.is_empty() && !selected.iter().any(|map| name == map.to_uppercase().as_str()) {
+            continue;
+        }
+        let lumps = MapLumps::at(&wad, index);
+        let found = match Map::from_lumps(&lumps) {
+            Ok(map) => lint_map(&map, &lumps, &resources).iter().map(ToString::to_string).collect(),
+            Err(e) => vec![e.to_string()],
+        };
+        for problem in &found {
+            println!("{}: {}", name, problem);
+        }
+        problems += found.len();
+    }
+
+    Ok(if problems == 0 { ExitCode::SUCCESS } else { ExitCode::from(1) })
+}
+
+fn render(args: &[String]) -> Result<ExitCode, String> {
+    let (positional, options) = parse_args(args, &["-o", "--size"], &["--things", "--tags"])?;
+    let [path, name] = positional[..] else { return Err(USAGE.to_string()) };
+    let out = options.get("-o").ok_or(USAGE.to_string())?;
+    let wad = open(path)?;
+    let map = Map::load(&wad, &name.to_uppercase()).map_err(|e| format!("{}: {}", path, e))?;
+
+    let overview = Overview {
+        things: options.contains_key("--things"),
+        tags: options.contains_key("--tags"),
+        size: match options.get("--size") {
+            Some(size) => size.parse().ok().filter(|&size| size > 0).ok_or(format!("bad size {}", size))?,
+            None => Overview::default().size,
+        },
+    };
+    let image = if out.to_lowercase().ends_with(".png") {
+        overview.png(&map)
+    } else {
+        overview.svg(&map).into_bytes()
+    };
+    fs::write(out, image).map_err(|e| format!("{}: {}", out, e))?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/**
+ * Case-insensitive match of a lump name against a pattern with `*` and `?` wildcards.
+ */
+fn glob_match(pattern: &str, name: &str) -> bool {
+    fn matches(pattern: &[u8], name: &[u8]) -> bool {
+        match (pattern.first(), name.first()) {
+            (None, None) => true,
+            (Some(b'*'), _) => matches(&pattern[1..], name) || (!name.is_empty() && matches(pattern, &name[1..])),
+            (Some(b'?'), Some(_)) => matches(&pattern[1..], &name[1..]),
+            (Some(p), Some(n)) => p.eq_ignore_ascii_case(n) && matches(&pattern[1..], &name[1..]),
+            _ => false,
+        }
+    }
+    matches(pattern.as_bytes(), name.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_lump_names() {
+        assert!(glob_match("*", "PLAYPAL"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("d_*", "D_E1M1"));
+        assert!(glob_match("E?M?", "E1M9"));
+        assert!(glob_match("*PAL*", "PLAYPAL"));
+        assert!(glob_match("TROO*A?", "TROOA1"));
+        assert!(!glob_match("E?M?", "E1M10"));
+        assert!(!glob_match("D_*", "DS_PISTOL"));
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("PLAYPAL", "PLAYPA"));
+    }
+}
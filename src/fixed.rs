@@ -0,0 +1,32 @@
+/**
+ * Doom's 16.16 fixed point number, used for map coordinates throughout the engine.
+ */
+pub type Fixed = i32;
+
+pub const FRACBITS: i32 = 16;
+pub const FRACUNIT: Fixed = 1 << FRACBITS;
+
+/**
+ * Converts whole map units to fixed point.
+ */
+pub fn to_fixed(units: i32) -> Fixed {
+    units << FRACBITS
+}
+
+/**
+ * Multiplies two fixed point numbers, vanilla's `FixedMul`.
+ */
+pub fn fixed_mul(a: Fixed, b: Fixed) -> Fixed {
+    ((i64::from(a) * i64::from(b)) >> FRACBITS) as Fixed
+}
+
+/**
+ * Divides two fixed point numbers, vanilla's `FixedDiv`: results that would overflow
+ * saturate to the largest value of the right sign.
+ */
+pub fn fixed_div(a: Fixed, b: Fixed) -> Fixed {
+    if (a.unsigned_abs() >> 14) >= b.unsigned_abs() {
+        return if (a ^ b) < 0 { Fixed::MIN } else { Fixed::MAX };
+    }
+    ((i64::from(a) << FRACBITS) / i64::from(b)) as Fixed
+}
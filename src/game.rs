@@ -0,0 +1,207 @@
+use std::fmt::{Display, Formatter};
+use crate::wad::Wad;
+
+/**
+ * Which release of the game the IWAD belongs to, deciding which episodes and maps exist.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GameMode {
+    // Doom shareware, episode 1 only
+    Shareware,
+    // Doom registered, episodes 1 to 3
+    Registered,
+    // The Ultimate Doom, episodes 1 to 4
+    Retail,
+    // Doom II and Final Doom, MAP01 to MAP32
+    Commercial,
+    // none of the above
+    Indetermined,
+}
+
+/**
+ * Which game the IWAD contains.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GameMission {
+    Doom,
+    Doom2,
+    PackTnt,
+    PackPlut,
+    PackChex,
+    FreeDoom1,
+    FreeDoom2,
+    None,
+}
+
+/**
+ * The result of identifying an IWAD.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Game {
+    pub mode: GameMode,
+    pub mission: GameMission,
+}
+
+struct KnownIwad {
+    md5: &'static str,
+    mode: GameMode,
+    mission: GameMission,
+}
+
+// MD5 sums of the commercial IWADs and their releases
+const KNOWN_IWADS: [KnownIwad; 9] = [
+    KnownIwad { md5: "762fd6d4b960d4b759730f01387a50a1", mode: GameMode::Shareware, mission: GameMission::Doom }, // 1.666
+    KnownIwad { md5: "11e1cd216801ea2657723abc86ecb01f", mode: GameMode::Shareware, mission: GameMission::Doom }, // 1.8
+    KnownIwad { md5: "f0cefca49926d00903cf57551d901abe", mode: GameMode::Shareware, mission: GameMission::Doom }, // 1.9
+    KnownIwad { md5: "1cd63c5ddff1bf8ce844237f580e9cf3", mode: GameMode::Registered, mission: GameMission::Doom }, // 1.9
+    KnownIwad { md5: "c4fe9fd920207691a9f493668e0a2083", mode: GameMode::Retail, mission: GameMission::Doom }, // 1.9ud
+    KnownIwad { md5: "25e1459ca71d321525f84628f45ca8cd", mode: GameMode::Commercial, mission: GameMission::Doom2 }, // 1.9
+    KnownIwad { md5: "4e158d9953c79ccf97bd0663244cc6b6", mode: GameMode::Commercial, mission: GameMission::PackTnt }, // 1.9
+    KnownIwad { md5: "75c8cf89566741fa9d22447604053bd7", mode: GameMode::Commercial, mission: GameMission::PackPlut }, // 1.9
+    KnownIwad { md5: "25485721882b050afa96a56e5758dd52", mode: GameMode::Retail, mission: GameMission::PackChex },
+];
+
+impl Game {
+    /**
+     * Identifies the game in an IWAD from the lumps it contains, the way vanilla's
+     * `IdentifyVersion` checks for E4M1, E3M1 and MAP01, with lumps only found in Final Doom,
+     * Chex Quest and Freedoom telling those apart. Where the lumps only point to plain Doom II,
+     * shareware Doom or nothing at all, the checksum is looked up among the known releases.
+     * Other IWADs are not hashed, so a memory-mapped one is not read completely.
+     */
+    pub fn detect(iwad: &Wad) -> Game {
+        Game::detect_with(iwad, &KNOWN_IWADS)
+    }
+
+    fn detect_with(iwad: &Wad, known_iwads: &[KnownIwad]) -> Game {
+        let game = Game::from_lumps(iwad);
+        let certain = game.mission != GameMission::Doom2
+            && game.mode != GameMode::Shareware
+            && game.mode != GameMode::Indetermined;
+        if certain {
+            return game;
+        }
+
+        let md5 = format!("{:x}", md5::compute(iwad.as_bytes()));
+        known_iwads.iter()
+            .find(|known| known.md5 == md5)
+            .map_or(game, |known| Game { mode: known.mode, mission: known.mission })
+    }
+
+    fn from_lumps(iwad: &Wad) -> Game {
+        let has = |name: &str| iwad.index_of(name).is_some();
+
+        match (has("MAP01"), has("E1M1")) {
+            (true, false) => {
+                let mission = if has("FREEDOOM") {
+                    GameMission::FreeDoom2
+                } else if has("REDTNT2") {
+                    GameMission::PackTnt
+                } else if has("CAMO1") {
+                    GameMission::PackPlut
+                } else {
+                    GameMission::Doom2
+                };
+                Game { mode: GameMode::Commercial, mission }
+            }
+            // Chex Quest is built on The Ultimate Doom, whatever maps it has
+            (false, true) if has("W94_1") && has("POSSH0M0") => Game { mode: GameMode::Retail, mission: GameMission::PackChex },
+            (false, true) => {
+                let mode = if has("E4M1") {
+                    GameMode::Retail
+                } else if has("E3M1") {
+                    GameMode::Registered
+                } else {
+                    GameMode::Shareware
+                };
+                let mission = if has("FREEDOOM") { GameMission::FreeDoom1 } else { GameMission::Doom };
+                Game { mode, mission }
+            }
+            _ => Game { mode: GameMode::Indetermined, mission: GameMission::None },
+        }
+    }
+
+    /**
+     * Whether PWADs may be loaded on top of this game. Vanilla refuses them for shareware.
+     */
+    pub fn allows_pwads(&self) -> bool {
+        self.mode != GameMode::Shareware
+    }
+
+    /**
+     * Whether maps are named MAPxx rather than ExMy.
+     */
+    pub fn is_commercial(&self) -> bool {
+        self.mode == GameMode::Commercial
+    }
+}
+
+impl Display for Game {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match (self.mission, self.mode) {
+            (GameMission::Doom, GameMode::Shareware) => "DOOM Shareware",
+            (GameMission::Doom, GameMode::Registered) => "DOOM Registered",
+            (GameMission::Doom, GameMode::Retail) => "The Ultimate DOOM",
+            (GameMission::Doom2, _) => "DOOM 2: Hell on Earth",
+            (GameMission::PackTnt, _) => "Final DOOM: TNT: Evilution",
+            (GameMission::PackPlut, _) => "Final DOOM: The Plutonia Experiment",
+            (GameMission::PackChex, _) => "Chex Quest",
+            (GameMission::FreeDoom1, _) => "Freedoom: Phase 1",
+            (GameMission::FreeDoom2, _) => "Freedoom: Phase 2",
+            _ => "Unknown game",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wad::{Identification, WadWriter};
+
+    fn iwad(lumps: &[&str]) -> Wad {
+        let mut writer = WadWriter::new(Identification::IWAD);
+        for &lump in lumps {
+            writer.add_marker(lump);
+        }
+        Wad::from_bytes(writer.to_bytes()).unwrap()
+    }
+
+    fn detect(lumps: &[&str]) -> Game {
+        Game::detect(&iwad(lumps))
+    }
+
+    #[test]
+    fn detects_unlisted_iwads_from_their_lumps() {
+        assert_eq!(detect(&["MAP01", "REDTNT2"]).mission, GameMission::PackTnt);
+        assert_eq!(detect(&["MAP01", "CAMO1"]).mission, GameMission::PackPlut);
+        assert_eq!(detect(&["MAP01"]).mission, GameMission::Doom2);
+
+        let chex = detect(&["E1M1", "W94_1", "POSSH0M0"]);
+        assert_eq!(chex, Game { mode: GameMode::Retail, mission: GameMission::PackChex });
+        assert!(chex.allows_pwads());
+
+        assert_eq!(detect(&["E1M1"]), Game { mode: GameMode::Shareware, mission: GameMission::Doom });
+        assert_eq!(detect(&[]).mode, GameMode::Indetermined);
+    }
+
+    #[test]
+    fn checksums_override_uncertain_lumps() {
+        let doom2_like = iwad(&["MAP01", "PLUTONIA"]);
+        let md5: &'static str = format!("{:x}", md5::compute(doom2_like.as_bytes())).leak();
+        let known = [KnownIwad { md5, mode: GameMode::Commercial, mission: GameMission::PackPlut }];
+        assert_eq!(Game::detect_with(&doom2_like, &known).mission, GameMission::PackPlut);
+        assert_eq!(Game::detect_with(&doom2_like, &[]).mission, GameMission::Doom2);
+
+        let shareware_like = iwad(&["E1M1"]);
+        let md5: &'static str = format!("{:x}", md5::compute(shareware_like.as_bytes())).leak();
+        let known = [KnownIwad { md5, mode: GameMode::Registered, mission: GameMission::Doom }];
+        assert_eq!(Game::detect_with(&shareware_like, &known).mode, GameMode::Registered);
+    }
+
+    #[test]
+    fn detects_doom1_wad() {
+        let shareware = Wad::open(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/doom1.wad")).unwrap();
+        assert_eq!(Game::detect(&shareware), Game { mode: GameMode::Shareware, mission: GameMission::Doom });
+    }
+}
@@ -0,0 +1,51 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use crate::wad::LumpName;
+
+mod flat;
+mod palette;
+mod picture;
+mod sprite;
+mod texture;
+
+pub use flat::{Flat, FlatSet, FLAT_SIZE};
+pub use palette::{ColorMap, Palette};
+pub use picture::{Picture, Post};
+pub use sprite::{SpriteDef, SpriteFrame, SpriteSet};
+pub use texture::{Texture, TexturePatch, TextureSet};
+pub(crate) use texture::texture_names;
+
+/**
+ * Everything that can go wrong while decoding graphics lumps.
+ */
+#[derive(Debug)]
+pub enum GraphicsError {
+    // a lump the graphics depend on is not loaded
+    MissingLump(LumpName),
+
+    // a lump is too short or inconsistent
+    BadLump { lump: LumpName, problem: &'static str },
+
+    // data given as a picture is not in the picture format
+    BadPicture(&'static str),
+
+    // a texture uses a patch that is in PNAMES but not loaded
+    MissingPatch { texture: LumpName, patch: LumpName },
+
+    // the lumps of a sprite frame do not make up its rotations
+    BadSprite { sprite: LumpName, frame: char, problem: &'static str },
+}
+
+impl Display for GraphicsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphicsError::MissingLump(lump) => write!(f, "no {} lump", lump),
+            GraphicsError::BadLump { lump, problem } => write!(f, "{}: {}", lump, problem),
+            GraphicsError::BadPicture(problem) => write!(f, "bad picture: {}", problem),
+            GraphicsError::MissingPatch { texture, patch } => write!(f, "texture {} uses missing patch {}", texture, patch),
+            GraphicsError::BadSprite { sprite, frame, problem } => write!(f, "sprite {} frame {} {}", sprite, frame, problem),
+        }
+    }
+}
+
+impl Error for GraphicsError {}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use crate::graphics::{GraphicsError, Palette};
+use crate::resource::{Namespace, ResourceStack};
+use crate::wad::LumpName;
+
+pub const FLAT_SIZE: usize = 64;
+
+/**
+ * A floor or ceiling texture: 64 by 64 palette indices, row by row.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Flat {
+    pub name: LumpName,
+    pixels: Box<[u8; FLAT_SIZE * FLAT_SIZE]>,
+}
+
+impl Flat {
+    /**
+     * Reads a flat from its lump. Like vanilla, only the first 4096 bytes are used.
+     */
+    pub fn from_lump(name: LumpName, lump: &[u8]) -> Result<Flat, GraphicsError> {
+        let pixels = lump.get(..FLAT_SIZE * FLAT_SIZE)
+            .ok_or(GraphicsError::BadLump { lump: name, problem: "shorter than a flat" })?;
+        Ok(Flat { name, pixels: Box::new(pixels.try_into().unwrap()) })
+    }
+
+    pub fn pixels(&self) -> &[u8; FLAT_SIZE * FLAT_SIZE] {
+        &self.pixels
+    }
+
+    /**
+     * The palette index at a point, the flat repeating in both directions.
+     */
+    pub fn pixel(&self, x: i32, y: i32) -> u8 {
+        let (x, y) = (x as usize & (FLAT_SIZE - 1), y as usize & (FLAT_SIZE - 1));
+        self.pixels[y * FLAT_SIZE + x]
+    }
+
+    /**
+     * The flat as RGBA pixels row by row.
+     */
+    pub fn to_rgba(&self, palette: &Palette) -> Vec<u8> {
+        self.pixels.iter()
+            .flat_map(|&index| {
+                let [r, g, b] = palette.rgb(index);
+                [r, g, b, 0xff]
+            })
+            .collect()
+    }
+}
+
+/**
+ * The flats of the merged flats namespace, numbered in its order so that animated flats
+ * follow each other as they do in vanilla. Unlike in vanilla, the IWADs' inner markers such
+ * as F1_START take no number. Flats are only looked up in their namespace, so they can share
+ * names with textures, sprites and other lumps.
+ *
+ * Lumps too short to be a flat keep their number, so the flats after them are not renumbered,
+ * but have no pixels; they are listed in `errors`.
+ */
+pub struct FlatSet {
+    flats: Vec<Option<Flat>>,
+    numbers: HashMap<LumpName, usize>,
+    errors: Vec<GraphicsError>,
+}
+
+impl FlatSet {
+    pub fn load(resources: &ResourceStack) -> FlatSet {
+        let mut flats = Vec::new();
+        let mut numbers = HashMap::new();
+        let mut errors = Vec::new();
+        for &num in resources.namespace(Namespace::Flats) {
+            let (Some(name), Some(lump)) = (resources.lump_name(num), resources.lump_data(num)) else { continue };
+            numbers.insert(name, flats.len());
+            match Flat::from_lump(name, lump) {
+                Ok(flat) => flats.push(Some(flat)),
+                Err(error) => {
+                    errors.push(error);
+                    flats.push(None);
+                }
+            }
+        }
+        FlatSet { flats, numbers, errors }
+    }
+
+    /**
+     * How many flat numbers there are, including those of lumps that are not flats.
+     */
+    pub fn len(&self) -> usize {
+        self.flats.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flats.is_empty()
+    }
+
+    /**
+     * The number of a flat, as sectors refer to it in savegames and animations.
+     */
+    pub fn number(&self, name: &str) -> Option<usize> {
+        self.numbers.get(&LumpName::new(name)).copied()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Flat> {
+        self.number(name).and_then(|number| self.flat(number))
+    }
+
+    pub fn flat(&self, number: usize) -> Option<&Flat> {
+        self.flats.get(number)?.as_ref()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Flat> {
+        self.flats.iter().flatten()
+    }
+
+    /**
+     * The lumps in the flats namespace that could not be read as flats.
+     */
+    pub fn errors(&self) -> &[GraphicsError] {
+        &self.errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wad::{Identification, Wad, WadWriter};
+
+    #[test]
+    fn short_lumps_keep_their_number() {
+        let mut iwad = WadWriter::new(Identification::IWAD);
+        iwad.add_marker("F_START")
+            .add_marker("F1_START")
+            .add_lump("NUKAGE1", vec![1; FLAT_SIZE * FLAT_SIZE])
+            .add_lump("NUKAGE2", vec![2; 64])
+            .add_lump("NUKAGE3", vec![3; FLAT_SIZE * FLAT_SIZE + 8])
+            .add_marker("F1_END")
+            .add_marker("F_END");
+        let flats = FlatSet::load(&ResourceStack::new(Wad::from_bytes(iwad.to_bytes()).unwrap()));
+
+        assert_eq!(flats.len(), 3);
+        assert_eq!((flats.number("NUKAGE1"), flats.number("NUKAGE2"), flats.number("NUKAGE3")), (Some(0), Some(1), Some(2)));
+        assert!(flats.get("NUKAGE2").is_none());
+        assert_eq!(flats.get("NUKAGE3").unwrap().pixel(65, -1), 3);
+        assert_eq!(flats.iter().count(), 2);
+        assert!(matches!(flats.errors(), [GraphicsError::BadLump { lump, .. }] if *lump == "NUKAGE2"));
+    }
+}
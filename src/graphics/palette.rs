@@ -0,0 +1,116 @@
+use crate::graphics::GraphicsError;
+use crate::resource::ResourceStack;
+use crate::wad::LumpName;
+
+const PALETTE_SIZE: usize = 256 * 3;
+const COLORMAP_SIZE: usize = 256;
+
+/**
+ * One of the palettes in PLAYPAL. Vanilla has 14: the normal one, 8 increasingly red ones for
+ * pain and berserk, 4 yellow ones for picking up items and a green one for the radiation suit.
+ */
+#[derive(Clone, PartialEq, Eq)]
+pub struct Palette {
+    colors: [[u8; 3]; 256],
+}
+
+impl Palette {
+    pub const NORMAL: usize = 0;
+    pub const FIRST_PAIN: usize = 1;
+    pub const FIRST_PICKUP: usize = 9;
+    pub const RADIATION_SUIT: usize = 13;
+
+    /**
+     * Decodes all palettes of a PLAYPAL lump.
+     */
+    pub fn from_playpal(lump: &[u8]) -> Result<Vec<Palette>, GraphicsError> {
+        if lump.len() < PALETTE_SIZE {
+            return Err(GraphicsError::BadLump { lump: LumpName::new("PLAYPAL"), problem: "shorter than one palette" });
+        }
+        Ok(lump.chunks_exact(PALETTE_SIZE)
+            .map(|palette| Palette { colors: std::array::from_fn(|i| [palette[i * 3], palette[i * 3 + 1], palette[i * 3 + 2]]) })
+            .collect())
+    }
+
+    /**
+     * The palettes of the PLAYPAL lump that was loaded last.
+     */
+    pub fn load(resources: &ResourceStack) -> Result<Vec<Palette>, GraphicsError> {
+        let lump = resources.find("PLAYPAL")
+            .and_then(|num| resources.lump_data(num))
+            .ok_or(GraphicsError::MissingLump(LumpName::new("PLAYPAL")))?;
+        Palette::from_playpal(lump)
+    }
+
+    pub fn rgb(&self, index: u8) -> [u8; 3] {
+        self.colors[usize::from(index)]
+    }
+
+    pub fn colors(&self) -> &[[u8; 3]; 256] {
+        &self.colors
+    }
+}
+
+/**
+ * The COLORMAP light tables, mapping every palette index to the index of the same colour
+ * at a lower light level. Vanilla has 34: 32 light levels from brightest to darkest, the
+ * inverse greyscale of the invulnerability power-up and an all black one.
+ */
+#[derive(Clone, PartialEq, Eq)]
+pub struct ColorMap {
+    tables: Vec<[u8; COLORMAP_SIZE]>,
+}
+
+impl ColorMap {
+    pub const LIGHT_LEVELS: usize = 32;
+    pub const INVULNERABILITY: usize = 32;
+
+    pub fn from_lump(lump: &[u8]) -> Result<ColorMap, GraphicsError> {
+        if lump.len() < COLORMAP_SIZE * ColorMap::LIGHT_LEVELS {
+            return Err(GraphicsError::BadLump { lump: LumpName::new("COLORMAP"), problem: "shorter than 32 light levels" });
+        }
+        Ok(ColorMap { tables: lump.chunks_exact(COLORMAP_SIZE).map(|table| table.try_into().unwrap()).collect() })
+    }
+
+    /**
+     * The COLORMAP lump that was loaded last.
+     */
+    pub fn load(resources: &ResourceStack) -> Result<ColorMap, GraphicsError> {
+        let lump = resources.find("COLORMAP")
+            .and_then(|num| resources.lump_data(num))
+            .ok_or(GraphicsError::MissingLump(LumpName::new("COLORMAP")))?;
+        ColorMap::from_lump(lump)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tables.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    /**
+     * One of the tables, 0 being full brightness.
+     */
+    pub fn table(&self, number: usize) -> Option<&[u8; COLORMAP_SIZE]> {
+        self.tables.get(number)
+    }
+
+    /**
+     * The palette index a colour takes at a light level from 0 (brightest) to 31 (darkest).
+     * Darker levels give the darkest table.
+     */
+    pub fn shade(&self, light: usize, index: u8) -> u8 {
+        self.tables[light.min(ColorMap::LIGHT_LEVELS - 1)][usize::from(index)]
+    }
+
+    /**
+     * The light level a sector's light (0 to 255) gives to surfaces right in front of the
+     * player, before vanilla darkens them further with distance.
+     */
+    pub fn sector_light(light_level: i16) -> usize {
+        let light = usize::try_from(light_level.clamp(0, 255)).unwrap();
+        (255 - light) >> 3
+    }
+}
@@ -0,0 +1,202 @@
+use crate::graphics::{GraphicsError, Palette};
+
+// Width, height, left and top offset
+const HEADER_SIZE: usize = 8;
+
+// Larger pictures are taken for other data that happens to parse
+const MAX_SIZE: usize = 4096;
+
+/**
+ * A vertical run of opaque pixels in a picture column.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Post {
+    // row of the first pixel
+    pub top: usize,
+    // palette indices
+    pub pixels: Vec<u8>,
+}
+
+/**
+ * A picture in Doom's patch format, used for wall patches, sprites and the status bar, menu
+ * and intermission graphics. Every column is a list of posts; pixels outside of them are
+ * transparent.
+ *
+ * Tall patches, whose posts start below row 254, are supported the DeePsea way: a post
+ * starting at or above the previous one continues counting from it. Column offsets that
+ * point outside of the lump give empty columns and posts running past its end are cut off,
+ * as PWADs in the wild contain both.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Picture {
+    pub width: usize,
+    pub height: usize,
+    // how far the picture is drawn left of and above its position
+    pub left_offset: i16,
+    pub top_offset: i16,
+    columns: Vec<Vec<Post>>,
+}
+
+impl Picture {
+    pub fn from_bytes(data: &[u8]) -> Result<Picture, GraphicsError> {
+        let (width, height) = Picture::dimensions(data).ok_or(GraphicsError::BadPicture("truncated header"))?;
+        if width == 0 || height == 0 {
+            return Err(GraphicsError::BadPicture("zero size"));
+        }
+
+        let columns = (0..width)
+            .map(|x| Picture::read_column(data, column_offset(data, x).unwrap()))
+            .collect();
+
+        Ok(Picture {
+            width,
+            height,
+            left_offset: i16::from_le_bytes([data[4], data[5]]),
+            top_offset: i16::from_le_bytes([data[6], data[7]]),
+            columns,
+        })
+    }
+
+    /**
+     * Whether a lump looks like a picture: a sensible size and all columns inside the lump.
+     * Used to tell pictures from other lumps, which do not carry a signature either.
+     */
+    pub fn is_picture(data: &[u8]) -> bool {
+        let Some((width, height)) = Picture::dimensions(data) else { return false };
+        (1..=MAX_SIZE).contains(&width) && (1..=MAX_SIZE).contains(&height)
+            && (0..width).all(|x| column_offset(data, x)
+                .is_some_and(|offset| offset >= HEADER_SIZE + width * 4 && offset < data.len()))
+    }
+
+    // width and height, if the header and column offsets are all there
+    fn dimensions(data: &[u8]) -> Option<(usize, usize)> {
+        let width = usize::from(u16::from_le_bytes([*data.first()?, *data.get(1)?]));
+        let height = usize::from(u16::from_le_bytes([*data.get(2)?, *data.get(3)?]));
+        (data.len() >= HEADER_SIZE + width * 4).then_some((width, height))
+    }
+
+    fn read_column(data: &[u8], mut offset: usize) -> Vec<Post> {
+        let mut posts = Vec::new();
+        let mut previous_top = None;
+
+        // each post: top row, length, a padding byte, the pixels and another padding byte
+        while let Some(&top) = data.get(offset) {
+            if top == 0xff {
+                break;
+            }
+            let Some(&length) = data.get(offset + 1) else { break };
+            let top = match previous_top {
+                Some(previous) if usize::from(top) <= previous => previous + usize::from(top),
+                _ => usize::from(top),
+            };
+
+            let start = offset + 3;
+            let end = (start + usize::from(length)).min(data.len());
+            posts.push(Post { top, pixels: data.get(start..end).unwrap_or_default().to_vec() });
+            if end < start + usize::from(length) {
+                break;
+            }
+
+            previous_top = Some(top);
+            offset = start + usize::from(length) + 1;
+        }
+
+        posts
+    }
+
+    pub fn column(&self, x: usize) -> &[Post] {
+        &self.columns[x]
+    }
+
+    pub fn columns(&self) -> &[Vec<Post>] {
+        &self.columns
+    }
+
+    /**
+     * The picture's palette indices row by row, `None` where it is transparent.
+     */
+    pub fn to_indexed(&self) -> Vec<Option<u8>> {
+        let mut pixels = vec![None; self.width * self.height];
+        for (x, column) in self.columns.iter().enumerate() {
+            for post in column {
+                for (y, &pixel) in (post.top..self.height).zip(&post.pixels) {
+                    pixels[y * self.width + x] = Some(pixel);
+                }
+            }
+        }
+        pixels
+    }
+
+    /**
+     * The picture as RGBA pixels row by row, transparent pixels with zero alpha.
+     */
+    pub fn to_rgba(&self, palette: &Palette) -> Vec<u8> {
+        self.to_indexed().iter()
+            .flat_map(|pixel| match pixel {
+                Some(index) => {
+                    let [r, g, b] = palette.rgb(*index);
+                    [r, g, b, 0xff]
+                }
+                None => [0, 0, 0, 0],
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn column_offset(data: &[u8], x: usize) -> Option<usize> {
+    let bytes = data.get(HEADER_SIZE + x * 4..HEADER_SIZE + x * 4 + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a picture one column wide, the column at `offset` and `column` after the header
+    fn lump(height: u16, offset: u32, column: &[u8]) -> Vec<u8> {
+        let mut data = vec![1, 0];
+        data.extend(height.to_le_bytes());
+        data.extend([0; 4]);
+        data.extend(offset.to_le_bytes());
+        data.extend(column);
+        data
+    }
+
+    #[test]
+    fn reads_tall_patches() {
+        let data = lump(300, 12, &[250, 2, 0, 1, 2, 0, 10, 1, 0, 3, 0, 254, 1, 0, 4, 0, 0xff]);
+        let picture = Picture::from_bytes(&data).unwrap();
+        assert_eq!(picture.column(0), [
+            Post { top: 250, pixels: vec![1, 2] },
+            Post { top: 260, pixels: vec![3] },
+            Post { top: 514, pixels: vec![4] },
+        ]);
+        assert_eq!(picture.to_indexed()[260], Some(3));
+    }
+
+    #[test]
+    fn columns_outside_the_lump_are_empty() {
+        let data = lump(8, 1000, &[0, 1, 0, 7, 0, 0xff]);
+        let picture = Picture::from_bytes(&data).unwrap();
+        assert!(picture.column(0).is_empty());
+        assert!(!Picture::is_picture(&data));
+    }
+
+    #[test]
+    fn posts_running_past_the_end_are_cut_off() {
+        let data = lump(8, 12, &[0, 1, 0, 7, 0, 2, 6, 0, 8, 9]);
+        let picture = Picture::from_bytes(&data).unwrap();
+        assert_eq!(picture.column(0), [Post { top: 0, pixels: vec![7] }, Post { top: 2, pixels: vec![8, 9] }]);
+
+        let data = lump(8, 12, &[0, 1]);
+        assert_eq!(Picture::from_bytes(&data).unwrap().column(0), [Post { top: 0, pixels: vec![] }]);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert!(matches!(Picture::from_bytes(&[1, 0, 1]), Err(GraphicsError::BadPicture("truncated header"))));
+        assert!(matches!(Picture::from_bytes(&[2, 0, 1, 0, 0, 0, 0, 0, 16, 0, 0, 0]),
+                         Err(GraphicsError::BadPicture("truncated header"))));
+        assert!(matches!(Picture::from_bytes(&lump(0, 12, &[0xff])), Err(GraphicsError::BadPicture("zero size"))));
+    }
+}
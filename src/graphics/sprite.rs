@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use crate::graphics::GraphicsError;
+use crate::resource::{Namespace, ResourceStack};
+use crate::wad::LumpName;
+
+// Frames A to ], as far as vanilla's frame table goes
+const MAX_FRAMES: usize = 29;
+
+/**
+ * The pictures of one sprite frame. A frame either has a picture for each of the 8 angles
+ * it can be seen from, starting from the front and going anticlockwise in steps of 45
+ * degrees, or a single picture for all of them.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpriteFrame {
+    pub rotate: bool,
+    // lump numbers in the resource stack
+    pub lumps: [usize; 8],
+    // whether a picture is drawn mirrored
+    pub flip: [bool; 8],
+}
+
+impl SpriteFrame {
+    /**
+     * The lump to draw for a rotation from 0 to 7 and whether to mirror it.
+     */
+    pub fn view(&self, rotation: usize) -> (usize, bool) {
+        let rotation = if self.rotate { rotation } else { 0 };
+        (self.lumps[rotation], self.flip[rotation])
+    }
+}
+
+/**
+ * A sprite and its frames, frame 0 being A.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpriteDef {
+    pub name: LumpName,
+    pub frames: Vec<SpriteFrame>,
+}
+
+/**
+ * The sprites of the merged sprites namespace, put together the way R_InitSprites does it.
+ *
+ * Sprite lumps are named after the sprite, a frame letter and a rotation digit, 0 for one
+ * picture for all rotations and 1 to 8 for one per angle. A second frame letter and rotation
+ * may follow, for a rotation that uses the same picture mirrored, so TROOA2A8 is frame A of
+ * TROO seen from angle 2 and, mirrored, from angle 8.
+ */
+pub struct SpriteSet {
+    sprites: Vec<SpriteDef>,
+    numbers: HashMap<LumpName, usize>,
+}
+
+// What is known about a frame while its lumps are collected, None meaning nothing yet
+struct FrameBuilder {
+    rotate: Option<bool>,
+    lumps: [Option<usize>; 8],
+    flip: [bool; 8],
+}
+
+impl SpriteSet {
+    /**
+     * Builds the sprites called `names`, numbered in that order like vanilla's sprite name
+     * table. Sprites without lumps have no frames. Like vanilla, this fails if a frame is
+     * missing rotations, has a lump for one rotation twice or has both kinds of lumps, or
+     * if a frame between the first and the last one of a sprite has no lumps at all.
+     */
+    pub fn load(resources: &ResourceStack, names: &[&str]) -> Result<SpriteSet, GraphicsError> {
+        let lumps: Vec<(LumpName, usize)> = resources.namespace(Namespace::Sprites).iter()
+            .filter_map(|&num| Some((resources.lump_name(num)?, num)))
+            .collect();
+
+        let mut sprites = Vec::with_capacity(names.len());
+        for name in names {
+            let name = LumpName::new(name);
+            let mut frames: Vec<FrameBuilder> = Vec::new();
+
+            for &(lump, num) in lumps.iter().filter(|(lump, _)| lump.as_bytes().get(..4) == Some(name.as_bytes())) {
+                install_lump(name, lump, &lump.as_bytes()[4..], num, false, &mut frames)?;
+                if let Some(mirrored) = lump.as_bytes().get(6..).filter(|rest| !rest.is_empty()) {
+                    install_lump(name, lump, mirrored, num, true, &mut frames)?;
+                }
+            }
+
+            let frames = frames.into_iter().enumerate()
+                .map(|(frame, builder)| {
+                    let bad = |problem| GraphicsError::BadSprite { sprite: name, frame: frame_letter(frame), problem };
+                    let rotate = builder.rotate.ok_or(bad("has no lumps"))?;
+                    // rotation 0 lumps fill all 8
+                    let lumps = builder.lumps.iter()
+                        .map(|lump| lump.ok_or(bad("is missing rotations")))
+                        .collect::<Result<Vec<usize>, GraphicsError>>()?;
+                    Ok(SpriteFrame { rotate, lumps: lumps.try_into().unwrap(), flip: builder.flip })
+                })
+                .collect::<Result<Vec<SpriteFrame>, GraphicsError>>()?;
+            sprites.push(SpriteDef { name, frames });
+        }
+
+        let numbers = sprites.iter().enumerate().map(|(number, sprite)| (sprite.name, number)).collect();
+        Ok(SpriteSet { sprites, numbers })
+    }
+
+    /**
+     * The names of all sprites in the sprites namespace, in the order they first appear.
+     */
+    pub fn names(resources: &ResourceStack) -> Vec<LumpName> {
+        let mut names = Vec::new();
+        for name in resources.namespace(Namespace::Sprites).iter().filter_map(|&num| resources.lump_name(num)) {
+            let Some(prefix) = name.as_bytes().get(..4) else { continue };
+            let prefix = LumpName::from_bytes(prefix);
+            if !names.contains(&prefix) {
+                names.push(prefix);
+            }
+        }
+        names
+    }
+
+    pub fn len(&self) -> usize {
+        self.sprites.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sprites.is_empty()
+    }
+
+    pub fn number(&self, name: &str) -> Option<usize> {
+        self.numbers.get(&LumpName::new(name)).copied()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SpriteDef> {
+        self.number(name).map(|number| &self.sprites[number])
+    }
+
+    pub fn sprite(&self, number: usize) -> Option<&SpriteDef> {
+        self.sprites.get(number)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SpriteDef> {
+        self.sprites.iter()
+    }
+}
+
+// R_InstallSpriteLump, for the frame letter and rotation digit at the start of `code`
+fn install_lump(sprite: LumpName, lump: LumpName, code: &[u8], num: usize, flipped: bool,
+                frames: &mut Vec<FrameBuilder>) -> Result<(), GraphicsError> {
+    let bad_characters = || GraphicsError::BadLump { lump, problem: "bad sprite frame characters" };
+    let (&frame, &rotation) = code.first().zip(code.get(1)).ok_or_else(bad_characters)?;
+    let frame = usize::from(frame.wrapping_sub(b'A'));
+    let rotation = usize::from(rotation.wrapping_sub(b'0'));
+    if frame >= MAX_FRAMES || rotation > 8 {
+        return Err(bad_characters());
+    }
+
+    while frames.len() <= frame {
+        frames.push(FrameBuilder { rotate: None, lumps: [None; 8], flip: [false; 8] });
+    }
+    let bad = |problem| GraphicsError::BadSprite { sprite, frame: frame_letter(frame), problem };
+    let builder = &mut frames[frame];
+
+    if rotation == 0 {
+        match builder.rotate {
+            Some(false) => return Err(bad("has several rotation 0 lumps")),
+            Some(true) => return Err(bad("has rotations and a rotation 0 lump")),
+            None => {}
+        }
+        builder.rotate = Some(false);
+        builder.lumps = [Some(num); 8];
+        builder.flip = [flipped; 8];
+        return Ok(());
+    }
+
+    if builder.rotate == Some(false) {
+        return Err(bad("has rotations and a rotation 0 lump"));
+    }
+    builder.rotate = Some(true);
+    if builder.lumps[rotation - 1].is_some() {
+        return Err(bad("has two lumps for one rotation"));
+    }
+    builder.lumps[rotation - 1] = Some(num);
+    builder.flip[rotation - 1] = flipped;
+    Ok(())
+}
+
+fn frame_letter(frame: usize) -> char {
+    char::from(b'A' + frame as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wad::{Identification, Wad, WadWriter};
+
+    // a WAD with the sprite lumps `lumps` between S_START and S_END
+    fn wad(identification: Identification, lumps: &[&str]) -> Wad {
+        let mut writer = WadWriter::new(identification);
+        writer.add_marker("S_START");
+        for &lump in lumps {
+            writer.add_lump(lump, lump.as_bytes().to_vec());
+        }
+        writer.add_marker("S_END");
+        Wad::from_bytes(writer.to_bytes()).unwrap()
+    }
+
+    fn load(lumps: &[&str]) -> Result<SpriteSet, GraphicsError> {
+        SpriteSet::load(&ResourceStack::new(wad(Identification::IWAD, lumps)), &["TROO"])
+    }
+
+    fn problem(result: Result<SpriteSet, GraphicsError>) -> &'static str {
+        match result {
+            Err(GraphicsError::BadSprite { problem, .. }) => problem,
+            _ => panic!("sprite loaded"),
+        }
+    }
+
+    #[test]
+    fn mirrors_lumps_named_for_two_rotations() {
+        let sprites = load(&["TROOA1", "TROOA2A8", "TROOA3A7", "TROOA4A6", "TROOA5", "TROOB0"]).unwrap();
+        let troo = sprites.get("TROO").unwrap();
+        let (a2a8, a3a7) = (2, 3);
+
+        assert!(troo.frames[0].rotate);
+        assert_eq!(troo.frames[0].view(1), (a2a8, false));
+        assert_eq!(troo.frames[0].view(7), (a2a8, true));
+        assert_eq!(troo.frames[0].view(6), (a3a7, true));
+        assert!(!troo.frames[1].rotate);
+        assert_eq!(troo.frames[1].view(5), (6, false));
+    }
+
+    #[test]
+    fn rejects_incomplete_frames() {
+        assert_eq!(problem(load(&["TROOA0", "TROOA1"])), "has rotations and a rotation 0 lump");
+        assert_eq!(problem(load(&["TROOA1", "TROOA0"])), "has rotations and a rotation 0 lump");
+        assert_eq!(problem(load(&["TROOA1", "TROOA2A8", "TROOA3A7", "TROOA4A6"])), "is missing rotations");
+        assert_eq!(problem(load(&["TROOA0", "TROOC0"])), "has no lumps");
+        assert_eq!(problem(load(&["TROOA0", "TROOA0B0", "TROOB0"])), "has several rotation 0 lumps");
+    }
+
+    #[test]
+    fn pwad_sprites_replace_iwad_ones() {
+        let mut resources = ResourceStack::new(wad(Identification::IWAD, &["TROOA0", "TROOB0"]));
+        resources.add_pwad(wad(Identification::PWAD, &["TROOB0"])).unwrap();
+        let sprites = SpriteSet::load(&resources, &["TROO", "SARG"]).unwrap();
+
+        let troo = sprites.get("TROO").unwrap();
+        assert_eq!(troo.frames[0].view(0).0, 1);
+        // the PWAD's lumps come after the four of the IWAD
+        assert_eq!(troo.frames[1].view(0).0, 5);
+        assert!(sprites.get("SARG").unwrap().frames.is_empty());
+        assert_eq!(sprites.number("SARG"), Some(1));
+    }
+}
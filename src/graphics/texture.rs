@@ -0,0 +1,343 @@
+use std::collections::{HashMap, HashSet};
+use crate::graphics::picture::column_offset;
+use crate::graphics::{GraphicsError, Picture, Post};
+use crate::resource::ResourceStack;
+use crate::wad::LumpName;
+
+// Name, masked flag, width, height, column directory and patch count
+const TEXTURE_HEADER_SIZE: usize = 22;
+
+// Origin, patch number and the unused step direction and colormap
+const TEXTURE_PATCH_SIZE: usize = 10;
+
+/**
+ * A patch placed in a texture, `origin_y` may be negative.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TexturePatch {
+    pub origin_x: i16,
+    pub origin_y: i16,
+    pub patch: LumpName,
+}
+
+/**
+ * A wall texture composited from its patches the way vanilla does it, quirks included:
+ *
+ * - A column covered by a single patch is that patch's column. The solid wall renderer
+ *   reads it from the first post on as if it were one post filling the texture height,
+ *   ignoring the patch's y origin, gaps between posts and the post headers in between.
+ *   Masked two-sided walls draw its posts, again without the y origin.
+ * - Columns covered by several patches are composited into one opaque post. Parts of a
+ *   patch above the texture are not skipped; the patch is drawn from its first pixel at
+ *   the top of the texture and cut off at the bottom instead.
+ * - Textures repeat every power of two columns, so ones that are not a power of two wide
+ *   do not tile cleanly.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Texture {
+    pub name: LumpName,
+    pub width: usize,
+    pub height: usize,
+    pub patches: Vec<TexturePatch>,
+    width_mask: usize,
+    // column-major palette indices
+    pixels: Vec<u8>,
+    posts: Vec<Vec<Post>>,
+}
+
+impl Texture {
+    /**
+     * The palette indices of a column, top to bottom, as the solid wall renderer draws it.
+     * Columns outside the texture wrap around.
+     */
+    pub fn column(&self, x: i32) -> &[u8] {
+        let x = x as usize & self.width_mask;
+        &self.pixels[x * self.height..(x + 1) * self.height]
+    }
+
+    /**
+     * The posts of a column as masked walls draw them. Columns outside the texture wrap around.
+     */
+    pub fn posts(&self, x: i32) -> &[Post] {
+        &self.posts[x as usize & self.width_mask]
+    }
+}
+
+/**
+ * The wall textures defined in TEXTURE1 and TEXTURE2, numbered in that order.
+ */
+pub struct TextureSet {
+    textures: Vec<Texture>,
+    numbers: HashMap<LumpName, usize>,
+}
+
+impl TextureSet {
+    /**
+     * Composites the textures of the PNAMES, TEXTURE1 and TEXTURE2 lumps that were loaded
+     * last. Like vanilla's `W_GetNumForName`, patches are the lumps of their name loaded
+     * last, in or outside the patches namespace. This fails if a texture uses a patch that is
+     * not there.
+     */
+    pub fn load(resources: &ResourceStack) -> Result<TextureSet, GraphicsError> {
+        let lump = |name: &str| resources.find(name).and_then(|num| resources.lump_data(num));
+        let pnames = lump("PNAMES").ok_or(GraphicsError::MissingLump(LumpName::new("PNAMES")))?;
+        let patch_names = read_pnames(pnames)?;
+
+        let mut definitions = Vec::new();
+        for name in ["TEXTURE1", "TEXTURE2"] {
+            match lump(name) {
+                Some(data) => definitions.extend(read_textures(LumpName::new(name), data, &patch_names)?),
+                None if name == "TEXTURE1" => return Err(GraphicsError::MissingLump(LumpName::new(name))),
+                None => {}
+            }
+        }
+
+        // decoded once, as most patches are used by several textures
+        let mut patches: HashMap<LumpName, (Picture, &[u8])> = HashMap::new();
+        let mut textures = Vec::with_capacity(definitions.len());
+        for definition in definitions {
+            for patch in &definition.patches {
+                if patches.contains_key(&patch.patch) {
+                    continue;
+                }
+                let patch_name = patch.patch.to_string();
+                let data = resources.find(&patch_name)
+                    .and_then(|num| resources.lump_data(num))
+                    .ok_or(GraphicsError::MissingPatch { texture: definition.name, patch: patch.patch })?;
+                let picture = Picture::from_bytes(data)
+                    .map_err(|_| GraphicsError::BadLump { lump: patch.patch, problem: "not a picture" })?;
+                patches.insert(patch.patch, (picture, data));
+            }
+            textures.push(composite(definition, &patches));
+        }
+
+        // the first definition of a name wins
+        let mut numbers = HashMap::new();
+        for (number, texture) in textures.iter().enumerate() {
+            numbers.entry(texture.name).or_insert(number);
+        }
+        Ok(TextureSet { textures, numbers })
+    }
+
+    pub fn len(&self) -> usize {
+        self.textures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.textures.is_empty()
+    }
+
+    /**
+     * The number of a texture, as sidedefs refer to it in savegames and specials.
+     */
+    pub fn number(&self, name: &str) -> Option<usize> {
+        self.numbers.get(&LumpName::new(name)).copied()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Texture> {
+        self.number(name).map(|number| &self.textures[number])
+    }
+
+    pub fn texture(&self, number: usize) -> Option<&Texture> {
+        self.textures.get(number)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Texture> {
+        self.textures.iter()
+    }
+}
+
+fn read_pnames(data: &[u8]) -> Result<Vec<LumpName>, GraphicsError> {
+    let bad = |problem| GraphicsError::BadLump { lump: LumpName::new("PNAMES"), problem };
+    let count = read_i32(data, 0).ok_or(bad("truncated header"))?;
+    (0..usize::try_from(count).map_err(|_| bad("negative patch count"))?)
+        .map(|index| data.get(4 + index * 8..12 + index * 8).map(LumpName::from_bytes).ok_or(bad("truncated patch name")))
+        .collect()
+}
+
+// A texture as TEXTURE1 and TEXTURE2 define it
+struct Definition {
+    name: LumpName,
+    width: usize,
+    height: usize,
+    patches: Vec<TexturePatch>,
+}
+
+/**
+ * The names of the textures defined in the TEXTURE1 and TEXTURE2 lumps that were loaded last,
+ * without looking at their patches.
+ */
+pub(crate) fn texture_names(resources: &ResourceStack) -> Result<HashSet<LumpName>, GraphicsError> {
+    let mut names = HashSet::new();
+    for name in ["TEXTURE1", "TEXTURE2"] {
+        let Some(data) = resources.find(name).and_then(|num| resources.lump_data(num)) else { continue };
+        for offset in texture_offsets(LumpName::new(name), data)? {
+            names.insert(LumpName::from_bytes(&data[offset..offset + 8]));
+        }
+    }
+    Ok(names)
+}
+
+// Where each texture of a TEXTURE1 or TEXTURE2 lump starts, checked to hold a whole header
+fn texture_offsets(lump: LumpName, data: &[u8]) -> Result<Vec<usize>, GraphicsError> {
+    let bad = |problem| GraphicsError::BadLump { lump, problem };
+    let count = read_i32(data, 0).ok_or(bad("truncated header"))?;
+    let count = usize::try_from(count).map_err(|_| bad("negative texture count"))?;
+
+    (0..count)
+        .map(|index| {
+            let offset = read_i32(data, 4 + index * 4).ok_or(bad("truncated texture offsets"))?;
+            let offset = usize::try_from(offset).map_err(|_| bad("negative texture offset"))?;
+            data.get(offset..offset + TEXTURE_HEADER_SIZE).ok_or(bad("texture outside the lump"))?;
+            Ok(offset)
+        })
+        .collect()
+}
+
+fn read_textures(lump: LumpName, data: &[u8], patch_names: &[LumpName]) -> Result<Vec<Definition>, GraphicsError> {
+    let bad = |problem| GraphicsError::BadLump { lump, problem };
+    let offsets = texture_offsets(lump, data)?;
+
+    let mut textures = Vec::with_capacity(offsets.len());
+    for offset in offsets {
+        let header = &data[offset..offset + TEXTURE_HEADER_SIZE];
+        let name = LumpName::from_bytes(&header[0..8]);
+        let width = i16::from_le_bytes([header[12], header[13]]);
+        let height = i16::from_le_bytes([header[14], header[15]]);
+        let patch_count = i16::from_le_bytes([header[20], header[21]]);
+        if width <= 0 || height <= 0 {
+            return Err(bad("texture without a size"));
+        }
+
+        let mut patches = Vec::new();
+        for patch in 0..usize::try_from(patch_count).unwrap_or(0) {
+            let start = offset + TEXTURE_HEADER_SIZE + patch * TEXTURE_PATCH_SIZE;
+            let entry = data.get(start..start + TEXTURE_PATCH_SIZE).ok_or(bad("truncated texture patches"))?;
+            let number = i16::from_le_bytes([entry[4], entry[5]]);
+            let patch_name = usize::try_from(number).ok()
+                .and_then(|number| patch_names.get(number))
+                .ok_or(bad("patch number outside PNAMES"))?;
+            patches.push(TexturePatch {
+                origin_x: i16::from_le_bytes([entry[0], entry[1]]),
+                origin_y: i16::from_le_bytes([entry[2], entry[3]]),
+                patch: *patch_name,
+            });
+        }
+        textures.push(Definition { name, width: width as usize, height: height as usize, patches });
+    }
+    Ok(textures)
+}
+
+fn read_i32(data: &[u8], offset: usize) -> Option<i32> {
+    Some(i32::from_le_bytes(data.get(offset..offset + 4)?.try_into().unwrap()))
+}
+
+fn composite(definition: Definition, pictures: &HashMap<LumpName, (Picture, &[u8])>) -> Texture {
+    let Definition { name, width, height, patches } = definition;
+    // the columns each patch covers, clipped to the texture
+    let spans: Vec<(i64, usize, usize)> = patches.iter()
+        .map(|patch| {
+            let origin = i64::from(patch.origin_x);
+            let picture = &pictures[&patch.patch].0;
+            let start = origin.clamp(0, width as i64) as usize;
+            let end = (origin + picture.width as i64).clamp(0, width as i64) as usize;
+            (origin, start, end)
+        })
+        .collect();
+
+    let mut pixels = vec![0; width * height];
+    let mut posts = Vec::with_capacity(width);
+    for x in 0..width {
+        let column = &mut pixels[x * height..(x + 1) * height];
+        let covering: Vec<usize> = (0..patches.len())
+            .filter(|&patch| (spans[patch].1..spans[patch].2).contains(&x))
+            .collect();
+
+        if let [patch] = covering[..] {
+            let (picture, data) = &pictures[&patches[patch].patch];
+            let patch_x = (x as i64 - spans[patch].0) as usize;
+            // the raw column data after the first post's header, whatever it holds
+            let raw = column_offset(data, patch_x).and_then(|offset| data.get(offset + 3..)).unwrap_or_default();
+            let length = raw.len().min(height);
+            column[..length].copy_from_slice(&raw[..length]);
+            posts.push(picture.column(patch_x).to_vec());
+            continue;
+        }
+
+        for &patch in &covering {
+            let picture = &pictures[&patches[patch].patch].0;
+            for post in picture.column((x as i64 - spans[patch].0) as usize) {
+                // vanilla clips the top without moving the source along
+                let mut position = i64::from(patches[patch].origin_y) + post.top as i64;
+                let mut count = post.pixels.len() as i64;
+                if position < 0 {
+                    count += position;
+                    position = 0;
+                }
+                count = count.min(height as i64 - position);
+                if count > 0 {
+                    let (position, count) = (position as usize, count as usize);
+                    column[position..position + count].copy_from_slice(&post.pixels[..count]);
+                }
+            }
+        }
+        posts.push(if covering.is_empty() { Vec::new() } else { vec![Post { top: 0, pixels: column.to_vec() }] });
+    }
+
+    // the largest power of two that fits
+    let mut repeat = 1;
+    while repeat * 2 <= width {
+        repeat *= 2;
+    }
+
+    Texture { name, width, height, patches, width_mask: repeat - 1, pixels, posts }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wad::{Identification, Wad, WadWriter};
+
+    // a 1 by 1 picture of a single palette index
+    fn picture(index: u8) -> Vec<u8> {
+        let mut data = vec![1, 0, 1, 0, 0, 0, 0, 0];
+        data.extend(12u32.to_le_bytes());
+        data.extend([0, 1, 0, index, 0, 0xff]);
+        data
+    }
+
+    // a 1 by 1 texture called WALL made of the patch WALL
+    fn texture_lumps(writer: &mut WadWriter) {
+        let mut pnames = 1i32.to_le_bytes().to_vec();
+        pnames.extend(b"WALL\0\0\0\0");
+        let mut texture1 = 1i32.to_le_bytes().to_vec();
+        texture1.extend(8i32.to_le_bytes());
+        texture1.extend(b"WALL\0\0\0\0");
+        texture1.extend([0, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 1, 0]);
+        texture1.extend([0, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+        writer.add_lump("PNAMES", pnames).add_lump("TEXTURE1", texture1);
+    }
+
+    #[test]
+    fn patches_loaded_last_win() {
+        let mut iwad = WadWriter::new(Identification::IWAD);
+        texture_lumps(&mut iwad);
+        iwad.add_marker("P_START").add_lump("WALL", picture(1)).add_marker("P_END");
+        let mut resources = ResourceStack::new(Wad::from_bytes(iwad.to_bytes()).unwrap());
+        assert_eq!(TextureSet::load(&resources).unwrap().get("WALL").unwrap().column(0), [1]);
+
+        // a PWAD patch outside of the namespace replaces the IWAD one
+        let mut pwad = WadWriter::new(Identification::PWAD);
+        pwad.add_lump("WALL", picture(2));
+        resources.add_pwad(Wad::from_bytes(pwad.to_bytes()).unwrap()).unwrap();
+        assert_eq!(TextureSet::load(&resources).unwrap().get("WALL").unwrap().column(0), [2]);
+    }
+
+    #[test]
+    fn missing_patches_fail() {
+        let mut iwad = WadWriter::new(Identification::IWAD);
+        texture_lumps(&mut iwad);
+        let resources = ResourceStack::new(Wad::from_bytes(iwad.to_bytes()).unwrap());
+        assert!(matches!(TextureSet::load(&resources), Err(GraphicsError::MissingPatch { .. })));
+    }
+}
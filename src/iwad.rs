@@ -0,0 +1,158 @@
+use std::env;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/**
+ * IWAD file names Room looks for when no `-iwad` is given, in order of preference.
+ */
+pub const IWAD_NAMES: [&str; 9] = [
+    "doom2.wad",
+    "plutonia.wad",
+    "tnt.wad",
+    "doom.wad",
+    "doom1.wad",
+    "chex.wad",
+    "freedoom2.wad",
+    "freedoom1.wad",
+    "freedm.wad",
+];
+
+/**
+ * The WAD related command line arguments.
+ */
+#[derive(Debug, Default)]
+pub struct LaunchOptions {
+    // -iwad <file>
+    pub iwad: Option<String>,
+
+    // -file <file> <file> ..., in load order
+    pub files: Vec<String>,
+}
+
+impl LaunchOptions {
+    /**
+     * Picks `-iwad` and `-file` out of the command line. Like vanilla, `-file` takes every
+     * following argument up to the next one starting with '-'.
+     */
+    pub fn parse(args: impl IntoIterator<Item = String>) -> LaunchOptions {
+        let mut options = LaunchOptions::default();
+        let mut args = args.into_iter().peekable();
+
+        while let Some(arg) = args.next() {
+            match arg.to_ascii_lowercase().as_str() {
+                "-iwad" => options.iwad = args.next(),
+                "-file" => {
+                    while let Some(file) = args.next_if(|next| !next.starts_with('-')) {
+                        options.files.push(file);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        options
+    }
+}
+
+/**
+ * A WAD that could not be found in any of the search directories.
+ */
+#[derive(Debug)]
+pub struct NotFound {
+    // the file looked for, `None` when searching for any known IWAD
+    pub name: Option<String>,
+    pub searched: Vec<PathBuf>,
+}
+
+impl Display for NotFound {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => writeln!(f, "Could not find {}. Looked in:", name)?,
+            None => writeln!(f, "Could not find an IWAD ({}). Looked in:", IWAD_NAMES.join(", "))?,
+        }
+        for dir in &self.searched {
+            writeln!(f, "  {}", dir.display())?;
+        }
+        write!(f, "Use -iwad <file>, or set DOOMWADDIR or DOOMWADPATH to the directory holding your WADs.")
+    }
+}
+
+impl Error for NotFound {}
+
+/**
+ * Directories searched for WADs: the working directory and its `resources` directory,
+ * DOOMWADDIR, each entry of DOOMWADPATH, the directory of the executable and the XDG data
+ * directories.
+ */
+pub fn search_dirs() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = vec![PathBuf::from("."), PathBuf::from("resources")];
+
+    if let Some(dir) = env::var_os("DOOMWADDIR") {
+        dirs.push(PathBuf::from(dir));
+    }
+    if let Some(path) = env::var_os("DOOMWADPATH") {
+        dirs.extend(env::split_paths(&path));
+    }
+    if let Some(dir) = env::current_exe().ok().as_deref().and_then(Path::parent) {
+        dirs.push(dir.to_path_buf());
+    }
+
+    let data_home = env::var_os("XDG_DATA_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")));
+    if let Some(data_home) = data_home {
+        dirs.push(data_home.join("games/doom"));
+    }
+    let data_dirs = env::var_os("XDG_DATA_DIRS").unwrap_or_else(|| "/usr/local/share:/usr/share".into());
+    dirs.extend(env::split_paths(&data_dirs).map(|dir| dir.join("games/doom")));
+
+    let mut unique = Vec::with_capacity(dirs.len());
+    for dir in dirs {
+        if !dir.as_os_str().is_empty() && !unique.contains(&dir) {
+            unique.push(dir);
+        }
+    }
+    unique
+}
+
+/**
+ * Locates the IWAD: the `-iwad` argument if given, otherwise the first known IWAD in the
+ * search directories.
+ */
+pub fn find_iwad(iwad: Option<&str>) -> Result<PathBuf, NotFound> {
+    if let Some(name) = iwad {
+        return find_wad(name);
+    }
+
+    let dirs = search_dirs();
+    for dir in &dirs {
+        if let Some(path) = IWAD_NAMES.iter().find_map(|name| find_in_dir(dir, name)) {
+            return Ok(path);
+        }
+    }
+    Err(NotFound { name: None, searched: dirs })
+}
+
+/**
+ * Locates a WAD given on the command line, either as a path or by file name in the search
+ * directories.
+ */
+pub fn find_wad(name: &str) -> Result<PathBuf, NotFound> {
+    let path = Path::new(name);
+    if path.is_file() {
+        return Ok(path.to_path_buf());
+    }
+
+    let dirs = search_dirs();
+    let file_name = path.file_name().and_then(|file_name| file_name.to_str()).unwrap_or(name);
+    dirs.iter()
+        .find_map(|dir| find_in_dir(dir, file_name))
+        .ok_or(NotFound { name: Some(name.to_string()), searched: dirs })
+}
+
+// WAD file names are often upper case on case sensitive file systems
+fn find_in_dir(dir: &Path, name: &str) -> Option<PathBuf> {
+    [name.to_string(), name.to_ascii_lowercase(), name.to_ascii_uppercase()].into_iter()
+        .map(|candidate| dir.join(candidate))
+        .find(|path| path.is_file())
+}
@@ -0,0 +1,10 @@
+pub mod fixed;
+pub mod game;
+pub mod graphics;
+pub mod iwad;
+pub mod map;
+pub mod music;
+pub mod png;
+pub mod resource;
+pub mod sound;
+pub mod wad;
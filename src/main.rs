@@ -1,17 +1,32 @@
-mod wad;
-
 extern crate sdl2;
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
+use sdl2::rect::Rect;
 use std::time::Duration;
-use crate::wad::load_wad_file;
+use room::graphics::Palette;
+use room::iwad::{find_iwad, find_wad, LaunchOptions};
+use room::resource::{open_container, ResourceStack};
+use room::wad::load_wad_file;
 
 pub fn main() -> Result<(), String> {
-    println!("Loading WAD file ...");
+    let options = LaunchOptions::parse(std::env::args().skip(1));
+
+    let iwad_path = find_iwad(options.iwad.as_deref()).map_err(|e| e.to_string())?;
+    println!("Loading IWAD {} ...", iwad_path.display());
+    let iwad = load_wad_file(&iwad_path).map_err(|e| format!("{}: {}", iwad_path.display(), e))?;
+    let mut resources = ResourceStack::new(iwad);
+    println!("{}", resources.game());
 
-    let wad= load_wad_file("resources/doom1.wad").expect("Could not find WAD file!");
+    for file in &options.files {
+        let path = find_wad(file).map_err(|e| e.to_string())?;
+        println!(" adding {}", path.display());
+        let pwad = open_container(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        resources.add_boxed(pwad).map_err(|e| e.to_string())?;
+    }
+
+    let palettes = Palette::load(&resources).map_err(|e| e.to_string())?;
 
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
@@ -25,7 +40,7 @@ pub fn main() -> Result<(), String> {
 
     let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
 
-    canvas.set_draw_color(Color::RGB(255, 255, 255));
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
     canvas.clear();
     canvas.present();
     let mut event_pump = sdl_context.event_pump()?;
@@ -43,7 +58,15 @@ pub fn main() -> Result<(), String> {
             }
         }
 
+        // until there is a renderer, show the colours of the normal palette in a 16x16 grid
+        canvas.set_draw_color(Color::RGB(0, 0, 0));
         canvas.clear();
+        let (width, height) = canvas.output_size()?;
+        for (index, [r, g, b]) in palettes[Palette::NORMAL].colors().iter().enumerate() {
+            let (column, row) = (index as u32 % 16, index as u32 / 16);
+            canvas.set_draw_color(Color::RGB(*r, *g, *b));
+            canvas.fill_rect(Rect::new((column * width / 16) as i32, (row * height / 16) as i32, width / 16, height / 16))?;
+        }
         canvas.present();
         ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 30));
     }
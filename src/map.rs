@@ -0,0 +1,437 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use crate::fixed::{to_fixed, Fixed, FRACBITS};
+use crate::resource::{ResourceContainer, ResourceStack};
+use crate::map::udmf::{Properties, UdmfNamespace};
+use crate::wad::LumpName;
+
+pub mod blockmap;
+pub mod bsp;
+pub mod lint;
+pub mod nodebuilder;
+pub mod overview;
+pub mod reject;
+pub mod sight;
+pub mod udmf;
+
+/**
+ * A map vertex. Binary maps store whole map units, the position is kept in fixed point like
+ * vanilla's `vertex_t`.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Vertex {
+    pub x: Fixed,
+    pub y: Fixed,
+}
+
+impl Vertex {
+    /**
+     * Position in whole map units, rounded towards negative infinity.
+     */
+    pub fn map_units(&self) -> (i32, i32) {
+        (self.x >> FRACBITS, self.y >> FRACBITS)
+    }
+}
+
+/**
+ * A line between two vertexes with the sidedefs facing either side of it.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Linedef {
+    pub start_vertex: usize,
+    pub end_vertex: usize,
+    pub flags: u16,
+    pub special: u16,
+    pub tag: u16,
+    // right side, looking from start to end vertex
+    pub front_sidedef: Option<usize>,
+    // left side, only set for two-sided lines
+    pub back_sidedef: Option<usize>,
+    // the special's arguments in Hexen format maps, which have no tag
+    pub args: Option<[i32; 5]>,
+}
+
+impl Linedef {
+    pub const BLOCKING: u16 = 0x0001;
+    pub const BLOCK_MONSTERS: u16 = 0x0002;
+    pub const TWO_SIDED: u16 = 0x0004;
+    pub const DONT_PEG_TOP: u16 = 0x0008;
+    pub const DONT_PEG_BOTTOM: u16 = 0x0010;
+    pub const SECRET: u16 = 0x0020;
+    pub const SOUND_BLOCK: u16 = 0x0040;
+    pub const DONT_DRAW: u16 = 0x0080;
+    pub const MAPPED: u16 = 0x0100;
+    // Boom: a use passes through the line to the lines behind it
+    pub const PASS_USE: u16 = 0x0200;
+    // Hexen: the special can be activated more than once, in place of PASS_USE
+    pub const REPEAT_SPECIAL: u16 = 0x0200;
+
+    /**
+     * The sidedef on side 0 (front) or 1 (back).
+     */
+    pub fn side(&self, side: usize) -> Option<usize> {
+        if side == 0 { self.front_sidedef } else { self.back_sidedef }
+    }
+}
+
+/**
+ * The textures of one side of a linedef and the sector that side faces.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sidedef {
+    pub x_offset: i16,
+    pub y_offset: i16,
+    pub upper_texture: LumpName,
+    pub lower_texture: LumpName,
+    pub middle_texture: LumpName,
+    pub sector: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sector {
+    pub floor_height: i16,
+    pub ceiling_height: i16,
+    pub floor_texture: LumpName,
+    pub ceiling_texture: LumpName,
+    pub light_level: i16,
+    pub special: u16,
+    pub tag: u16,
+}
+
+/**
+ * A map thing: player starts, monsters, items and decorations. The meaning of `flags`
+ * depends on the map format.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Thing {
+    pub x: Fixed,
+    pub y: Fixed,
+    pub angle: i16,
+    pub thing_type: u16,
+    pub flags: u16,
+    pub extended: Option<ExtendedThing>,
+}
+
+/**
+ * The fields Hexen format maps add to a thing.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExtendedThing {
+    // thing id, for scripts and specials to refer to
+    pub tid: i32,
+    // height above the floor
+    pub z: Fixed,
+    pub special: i32,
+    pub args: [i32; 5],
+}
+
+/**
+ * How a map's lumps are laid out. Hexen format maps are recognised by their BEHAVIOR lump,
+ * UDMF maps by their TEXTMAP lump.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapFormat {
+    Doom,
+    Hexen,
+    Udmf(UdmfNamespace),
+}
+
+/**
+ * Everything that can go wrong while loading a map.
+ */
+#[derive(Debug)]
+pub enum MapError {
+    // no marker lump with the map's name
+    MapNotFound(String),
+
+    // the map lacks one of its lumps
+    MissingLump { map: LumpName, lump: &'static str },
+
+    // a linedef refers to a vertex that does not exist
+    VertexOutOfRange { linedef: usize, vertex: usize },
+
+    // a linedef refers to a sidedef that does not exist
+    SidedefOutOfRange { linedef: usize, sidedef: usize },
+
+    // a sidedef refers to a sector that does not exist
+    SectorOutOfRange { sidedef: usize, sector: usize },
+
+    // an entry of the NODES, SEGS or SSECTORS lump is inconsistent
+    BadBsp { lump: &'static str, index: usize, problem: &'static str },
+
+    // the BLOCKMAP lump is inconsistent
+    BadBlockmap { problem: &'static str },
+
+    // the TEXTMAP of a UDMF map cannot be read
+    BadTextmap { line: usize, problem: String },
+}
+
+impl Display for MapError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MapError::MapNotFound(name) => write!(f, "map {} not found", name),
+            MapError::MissingLump { map, lump } => write!(f, "map {} has no {} lump", map, lump),
+            MapError::VertexOutOfRange { linedef, vertex } =>
+                write!(f, "linedef {} refers to vertex {} which does not exist", linedef, vertex),
+            MapError::SidedefOutOfRange { linedef, sidedef } =>
+                write!(f, "linedef {} refers to sidedef {} which does not exist", linedef, sidedef),
+            MapError::SectorOutOfRange { sidedef, sector } =>
+                write!(f, "sidedef {} refers to sector {} which does not exist", sidedef, sector),
+            MapError::BadBsp { lump, index, problem } => write!(f, "{} entry {}: {}", lump, index, problem),
+            MapError::BadBlockmap { problem } => write!(f, "BLOCKMAP: {}", problem),
+            MapError::BadTextmap { line, problem } => write!(f, "TEXTMAP line {}: {}", line, problem),
+        }
+    }
+}
+
+impl Error for MapError {}
+
+// Lumps that can follow a map marker, in any order. UDMF maps run from TEXTMAP to ENDMAP.
+const MAP_LUMP_NAMES: [&str; 14] = [
+    "THINGS", "LINEDEFS", "SIDEDEFS", "VERTEXES", "SEGS", "SSECTORS", "NODES", "SECTORS",
+    "REJECT", "BLOCKMAP", "BEHAVIOR", "SCRIPTS", "ZNODES", "TEXTMAP",
+];
+
+/**
+ * The lumps of one map: the marker lump (ExMy or MAPxx) and the lumps following it.
+ */
+pub struct MapLumps<'a> {
+    pub name: LumpName,
+    lumps: Vec<(LumpName, &'a [u8])>,
+}
+
+impl<'a> MapLumps<'a> {
+    /**
+     * Finds the last map called `name` in a container.
+     */
+    pub fn find<C: ResourceContainer + ?Sized>(container: &'a C, name: &str) -> Result<MapLumps<'a>, MapError> {
+        let marker = LumpName::new(name);
+        let index = (0..container.len()).rev()
+            .find(|&index| container.lump_name(index) == Some(marker))
+            .ok_or_else(|| MapError::MapNotFound(name.to_string()))?;
+        Ok(MapLumps::at(container, index))
+    }
+
+    /**
+     * Finds the map called `name` that was loaded last.
+     */
+    pub fn from_stack(stack: &'a ResourceStack, name: &str) -> Result<MapLumps<'a>, MapError> {
+        let (container, index) = stack.find(name)
+            .and_then(|num| stack.source(num))
+            .ok_or_else(|| MapError::MapNotFound(name.to_string()))?;
+        Ok(MapLumps::at(container, index))
+    }
+
+    /**
+     * The map whose marker lump is at `index` of the container.
+     */
+    pub fn at<C: ResourceContainer + ?Sized>(container: &'a C, index: usize) -> MapLumps<'a> {
+        let udmf = container.lump_name(index + 1).is_some_and(|name| name == "TEXTMAP");
+        let mut ended = false;
+        let lumps = (index + 1..container.len())
+            .map_while(|index| {
+                let name = container.lump_name(index)?;
+                let belongs = if udmf { !ended } else { MAP_LUMP_NAMES.iter().any(|lump| name == *lump) };
+                if !belongs {
+                    return None;
+                }
+                ended = name == "ENDMAP";
+                Some((name, container.lump_data(index)?))
+            })
+            .collect();
+
+        MapLumps {
+            name: container.lump_name(index).unwrap_or_default(),
+            lumps,
+        }
+    }
+
+    pub fn get(&self, lump: &str) -> Option<&'a [u8]> {
+        self.lumps.iter().find(|(name, _)| *name == lump).map(|&(_, data)| data)
+    }
+
+    pub fn require(&self, lump: &'static str) -> Result<&'a [u8], MapError> {
+        self.get(lump).ok_or(MapError::MissingLump { map: self.name, lump })
+    }
+
+    /**
+     * The lumps following the marker, in the order they are stored.
+     */
+    pub fn iter(&self) -> impl Iterator<Item = (LumpName, &'a [u8])> + '_ {
+        self.lumps.iter().copied()
+    }
+}
+
+/**
+ * The geometry and things of a map, with all cross references checked.
+ */
+pub struct Map {
+    pub name: LumpName,
+    pub format: MapFormat,
+    pub vertexes: Vec<Vertex>,
+    pub linedefs: Vec<Linedef>,
+    pub sidedefs: Vec<Sidedef>,
+    pub sectors: Vec<Sector>,
+    pub things: Vec<Thing>,
+    // the compiled ACS scripts of a Hexen format map
+    pub behavior: Option<Vec<u8>>,
+    // TEXTMAP fields without a place in the types above
+    pub properties: Properties,
+}
+
+impl Map {
+    /**
+     * Loads the last map called `name` from a container, e.g. a `Wad`.
+     */
+    pub fn load<C: ResourceContainer + ?Sized>(container: &C, name: &str) -> Result<Map, MapError> {
+        Map::from_lumps(&MapLumps::find(container, name)?)
+    }
+
+    pub fn from_lumps(lumps: &MapLumps) -> Result<Map, MapError> {
+        if let Some(textmap) = lumps.get("TEXTMAP") {
+            let map = udmf::parse(lumps.name, textmap)?;
+            map.validate()?;
+            return Ok(map);
+        }
+
+        let vertexes = records(lumps.require("VERTEXES")?, 4, |r| Vertex {
+            x: to_fixed(i16_at(r, 0).into()),
+            y: to_fixed(i16_at(r, 2).into()),
+        });
+
+        let behavior = lumps.get("BEHAVIOR");
+        let format = if behavior.is_some() { MapFormat::Hexen } else { MapFormat::Doom };
+        let args = |r: &[u8], offset: usize| -> [i32; 5] { std::array::from_fn(|arg| r[offset + arg].into()) };
+
+        let linedefs = match format {
+            MapFormat::Hexen => records(lumps.require("LINEDEFS")?, 16, |r| Linedef {
+                start_vertex: u16_at(r, 0).into(),
+                end_vertex: u16_at(r, 2).into(),
+                flags: u16_at(r, 4),
+                special: r[6].into(),
+                tag: 0,
+                front_sidedef: sidedef_index(u16_at(r, 12)),
+                back_sidedef: sidedef_index(u16_at(r, 14)),
+                args: Some(args(r, 7)),
+            }),
+            _ => records(lumps.require("LINEDEFS")?, 14, |r| Linedef {
+                start_vertex: u16_at(r, 0).into(),
+                end_vertex: u16_at(r, 2).into(),
+                flags: u16_at(r, 4),
+                special: u16_at(r, 6),
+                tag: u16_at(r, 8),
+                front_sidedef: sidedef_index(u16_at(r, 10)),
+                back_sidedef: sidedef_index(u16_at(r, 12)),
+                args: None,
+            }),
+        };
+
+        let sidedefs = records(lumps.require("SIDEDEFS")?, 30, |r| Sidedef {
+            x_offset: i16_at(r, 0),
+            y_offset: i16_at(r, 2),
+            upper_texture: LumpName::from_bytes(&r[4..12]),
+            lower_texture: LumpName::from_bytes(&r[12..20]),
+            middle_texture: LumpName::from_bytes(&r[20..28]),
+            sector: u16_at(r, 28).into(),
+        });
+
+        let sectors = records(lumps.require("SECTORS")?, 26, |r| Sector {
+            floor_height: i16_at(r, 0),
+            ceiling_height: i16_at(r, 2),
+            floor_texture: LumpName::from_bytes(&r[4..12]),
+            ceiling_texture: LumpName::from_bytes(&r[12..20]),
+            light_level: i16_at(r, 20),
+            special: u16_at(r, 22),
+            tag: u16_at(r, 24),
+        });
+
+        let things = match format {
+            MapFormat::Hexen => records(lumps.require("THINGS")?, 20, |r| Thing {
+                x: to_fixed(i16_at(r, 2).into()),
+                y: to_fixed(i16_at(r, 4).into()),
+                angle: i16_at(r, 8),
+                thing_type: u16_at(r, 10),
+                flags: u16_at(r, 12),
+                extended: Some(ExtendedThing {
+                    tid: i16_at(r, 0).into(),
+                    z: to_fixed(i16_at(r, 6).into()),
+                    special: r[14].into(),
+                    args: args(r, 15),
+                }),
+            }),
+            _ => records(lumps.require("THINGS")?, 10, |r| Thing {
+                x: to_fixed(i16_at(r, 0).into()),
+                y: to_fixed(i16_at(r, 2).into()),
+                angle: i16_at(r, 4),
+                thing_type: u16_at(r, 6),
+                flags: u16_at(r, 8),
+                extended: None,
+            }),
+        };
+
+        let map = Map {
+            name: lumps.name,
+            format,
+            vertexes,
+            linedefs,
+            sidedefs,
+            sectors,
+            things,
+            behavior: behavior.map(<[u8]>::to_vec),
+            properties: Properties::default(),
+        };
+        map.validate()?;
+        Ok(map)
+    }
+
+    fn validate(&self) -> Result<(), MapError> {
+        for (index, line) in self.linedefs.iter().enumerate() {
+            for vertex in [line.start_vertex, line.end_vertex] {
+                if vertex >= self.vertexes.len() {
+                    return Err(MapError::VertexOutOfRange { linedef: index, vertex });
+                }
+            }
+            for sidedef in [line.front_sidedef, line.back_sidedef].into_iter().flatten() {
+                if sidedef >= self.sidedefs.len() {
+                    return Err(MapError::SidedefOutOfRange { linedef: index, sidedef });
+                }
+            }
+        }
+
+        for (index, side) in self.sidedefs.iter().enumerate() {
+            if side.sector >= self.sectors.len() {
+                return Err(MapError::SectorOutOfRange { sidedef: index, sector: side.sector });
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * The sector on side 0 (front) or 1 (back) of a linedef.
+     */
+    pub fn side_sector(&self, linedef: usize, side: usize) -> Option<usize> {
+        self.linedefs.get(linedef)?.side(side).map(|sidedef| self.sidedefs[sidedef].sector)
+    }
+}
+
+// 0xFFFF (-1) marks a missing side
+fn sidedef_index(raw: u16) -> Option<usize> {
+    (raw != 0xffff).then_some(raw.into())
+}
+
+/**
+ * Decodes fixed size records. Like vanilla, trailing bytes that do not make up a whole
+ * record are ignored.
+ */
+pub(crate) fn records<T>(lump: &[u8], size: usize, decode: impl Fn(&[u8]) -> T) -> Vec<T> {
+    lump.chunks_exact(size).map(decode).collect()
+}
+
+pub(crate) fn i16_at(record: &[u8], offset: usize) -> i16 {
+    i16::from_le_bytes([record[offset], record[offset + 1]])
+}
+
+pub(crate) fn u16_at(record: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([record[offset], record[offset + 1]])
+}
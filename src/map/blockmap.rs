@@ -0,0 +1,396 @@
+use crate::fixed::{to_fixed, Fixed, FRACBITS};
+use crate::map::{i16_at, u16_at, Map, MapError, MapLumps};
+
+/**
+ * Blocks are 128 map units wide and high.
+ */
+pub const MAPBLOCKUNITS: i32 = 128;
+pub const MAPBLOCKSHIFT: i32 = FRACBITS + 7;
+
+// Boom rebuilds blockmaps this large, as their offsets cannot all fit into 16 bits
+const MAX_LUMP_WORDS: usize = 0x10000;
+
+/**
+ * The map's grid of 128 by 128 unit blocks, listing the linedefs crossing every block and the
+ * things standing in it.
+ *
+ * Like vanilla, every line list starts with linedef 0, whether or not it touches the block,
+ * so line 0 is checked everywhere. Things are linked and unlinked by the play code as they
+ * move and are iterated most recently linked first, as in vanilla's `blocklinks`.
+ */
+pub struct Blockmap {
+    pub origin_x: Fixed,
+    pub origin_y: Fixed,
+    pub columns: usize,
+    pub rows: usize,
+    lines: Vec<Vec<usize>>,
+    things: Vec<Vec<usize>>,
+}
+
+/**
+ * Marks the lines already visited by a query spanning several blocks, vanilla's
+ * `validcount`. Call `next` before every query.
+ */
+pub struct ValidCount {
+    count: u32,
+    marks: Vec<u32>,
+}
+
+impl ValidCount {
+    pub fn new(lines: usize) -> ValidCount {
+        ValidCount { count: 0, marks: vec![0; lines] }
+    }
+
+    pub fn next(&mut self) {
+        self.count = self.count.wrapping_add(1);
+    }
+
+    /**
+     * Marks a line, returning false if it was already marked during this query.
+     */
+    pub fn check(&mut self, line: usize) -> bool {
+        let Some(mark) = self.marks.get_mut(line) else { return false };
+        if *mark == self.count {
+            return false;
+        }
+        *mark = self.count;
+        true
+    }
+}
+
+impl Blockmap {
+    /**
+     * Loads the BLOCKMAP lump, or generates the blockmap when the lump is missing, broken or
+     * too large for its 16 bit offsets.
+     */
+    pub fn load_or_build(lumps: &MapLumps, map: &Map) -> Blockmap {
+        match lumps.get("BLOCKMAP") {
+            Some(lump) if lump.len() / 2 < MAX_LUMP_WORDS => {
+                Blockmap::from_lump(lump, map).unwrap_or_else(|_| Blockmap::build(map))
+            }
+            _ => Blockmap::build(map),
+        }
+    }
+
+    pub fn from_lump(lump: &[u8], map: &Map) -> Result<Blockmap, MapError> {
+        let bad = |problem| MapError::BadBlockmap { problem };
+        if lump.len() < 8 {
+            return Err(bad("the header is truncated"));
+        }
+
+        let columns = usize::from(u16_at(lump, 4));
+        let rows = usize::from(u16_at(lump, 6));
+        let blocks = columns * rows;
+        if lump.len() < 8 + blocks * 2 {
+            return Err(bad("the offset table is truncated"));
+        }
+
+        let mut lines = Vec::with_capacity(blocks);
+        for block in 0..blocks {
+            let mut offset = usize::from(u16_at(lump, 8 + block * 2)) * 2;
+            let mut list = Vec::new();
+            loop {
+                if offset + 2 > lump.len() {
+                    return Err(bad("a line list runs past the end of the lump"));
+                }
+                let line = u16_at(lump, offset);
+                if line == 0xffff {
+                    break;
+                }
+                if usize::from(line) >= map.linedefs.len() {
+                    return Err(bad("a line list refers to a linedef that does not exist"));
+                }
+                list.push(usize::from(line));
+                offset += 2;
+            }
+            lines.push(list);
+        }
+
+        Ok(Blockmap {
+            origin_x: to_fixed(i16_at(lump, 0).into()),
+            origin_y: to_fixed(i16_at(lump, 2).into()),
+            columns,
+            rows,
+            lines,
+            things: vec![Vec::new(); blocks],
+        })
+    }
+
+    /**
+     * Generates the blockmap of a map, adding every linedef to the blocks it passes through.
+     */
+    pub fn build(map: &Map) -> Blockmap {
+        let units = |value: Fixed| value >> FRACBITS;
+        let min_x = map.vertexes.iter().map(|v| units(v.x)).min().unwrap_or(0);
+        let max_x = map.vertexes.iter().map(|v| units(v.x)).max().unwrap_or(0);
+        let min_y = map.vertexes.iter().map(|v| units(v.y)).min().unwrap_or(0);
+        let max_y = map.vertexes.iter().map(|v| units(v.y)).max().unwrap_or(0);
+
+        let (origin_x, origin_y) = (min_x - 8, min_y - 8);
+        let columns = ((max_x - origin_x) / MAPBLOCKUNITS + 1) as usize;
+        let rows = ((max_y - origin_y) / MAPBLOCKUNITS + 1) as usize;
+        let mut lines = vec![vec![0]; columns * rows];
+
+        for (index, line) in map.linedefs.iter().enumerate() {
+            let start = map.vertexes[line.start_vertex];
+            let end = map.vertexes[line.end_vertex];
+            let (x1, y1) = (i64::from(units(start.x) - origin_x), i64::from(units(start.y) - origin_y));
+            let (x2, y2) = (i64::from(units(end.x) - origin_x), i64::from(units(end.y) - origin_y));
+            let block = i64::from(MAPBLOCKUNITS);
+
+            for row in y1.min(y2) / block..=y1.max(y2) / block {
+                for column in x1.min(x2) / block..=x1.max(x2) / block {
+                    let (left, bottom) = (column * block, row * block);
+                    if line_touches_box(x1, y1, x2, y2, left, bottom, left + block, bottom + block) {
+                        let list = &mut lines[row as usize * columns + column as usize];
+                        if list.last() != Some(&index) {
+                            list.push(index);
+                        }
+                    }
+                }
+            }
+        }
+
+        Blockmap {
+            origin_x: to_fixed(origin_x),
+            origin_y: to_fixed(origin_y),
+            columns,
+            rows,
+            things: vec![Vec::new(); lines.len()],
+            lines,
+        }
+    }
+
+    /**
+     * Encodes the blockmap as a BLOCKMAP lump, `None` if it is too large for the format's
+     * 16 bit offsets.
+     */
+    pub fn to_lump(&self) -> Option<Vec<u8>> {
+        let mut lump = Vec::new();
+        for value in [self.origin_x >> FRACBITS, self.origin_y >> FRACBITS] {
+            lump.extend((value as i16).to_le_bytes());
+        }
+        lump.extend(u16::try_from(self.columns).ok()?.to_le_bytes());
+        lump.extend(u16::try_from(self.rows).ok()?.to_le_bytes());
+
+        let mut offset = 4 + self.lines.len();
+        let mut lists = Vec::new();
+        for list in &self.lines {
+            lump.extend(u16::try_from(offset).ok()?.to_le_bytes());
+            for &line in list {
+                lists.extend(u16::try_from(line).ok()?.to_le_bytes());
+            }
+            lists.extend(0xffffu16.to_le_bytes());
+            offset += list.len() + 1;
+        }
+
+        lump.extend(lists);
+        Some(lump)
+    }
+
+    /**
+     * The block containing a point, which may lie outside the grid.
+     */
+    pub fn block_at(&self, x: Fixed, y: Fixed) -> (i32, i32) {
+        ((x - self.origin_x) >> MAPBLOCKSHIFT, (y - self.origin_y) >> MAPBLOCKSHIFT)
+    }
+
+    fn block_index(&self, column: i32, row: i32) -> Option<usize> {
+        let column = usize::try_from(column).ok().filter(|&column| column < self.columns)?;
+        let row = usize::try_from(row).ok().filter(|&row| row < self.rows)?;
+        Some(row * self.columns + column)
+    }
+
+    /**
+     * The linedefs listed for a block, nothing for blocks outside the grid.
+     */
+    pub fn lines_in_block(&self, column: i32, row: i32) -> impl Iterator<Item = usize> + '_ {
+        self.block_index(column, row).into_iter().flat_map(|block| self.lines[block].iter().copied())
+    }
+
+    /**
+     * The things linked into a block, most recently linked first.
+     */
+    pub fn things_in_block(&self, column: i32, row: i32) -> impl Iterator<Item = usize> + '_ {
+        self.block_index(column, row).into_iter().flat_map(|block| self.things[block].iter().rev().copied())
+    }
+
+    /**
+     * Calls `func` for the lines of a block not yet seen during the current query, stopping
+     * and returning false as soon as it does. Vanilla's `P_BlockLinesIterator`.
+     */
+    pub fn block_lines_iterator(&self, column: i32, row: i32, valid: &mut ValidCount,
+                                mut func: impl FnMut(usize) -> bool) -> bool {
+        self.lines_in_block(column, row).filter(|&line| valid.check(line)).all(&mut func)
+    }
+
+    /**
+     * Calls `func` for the things in a block, stopping and returning false as soon as it
+     * does. Vanilla's `P_BlockThingsIterator`.
+     */
+    pub fn block_things_iterator(&self, column: i32, row: i32, func: impl FnMut(usize) -> bool) -> bool {
+        self.things_in_block(column, row).all(func)
+    }
+
+    /**
+     * Links a thing into the block containing its position, returning false if the position
+     * is outside the grid. Vanilla's blockmap half of `P_SetThingPosition`.
+     */
+    pub fn link_thing(&mut self, thing: usize, x: Fixed, y: Fixed) -> bool {
+        let (column, row) = self.block_at(x, y);
+        let Some(block) = self.block_index(column, row) else { return false };
+        self.things[block].push(thing);
+        true
+    }
+
+    /**
+     * Unlinks a thing from the block containing the position it was linked at.
+     */
+    pub fn unlink_thing(&mut self, thing: usize, x: Fixed, y: Fixed) {
+        let (column, row) = self.block_at(x, y);
+        if let Some(block) = self.block_index(column, row) {
+            if let Some(position) = self.things[block].iter().rposition(|&linked| linked == thing) {
+                self.things[block].remove(position);
+            }
+        }
+    }
+}
+
+/**
+ * Whether a line touches a box, clipping it to the box like Liang-Barsky.
+ */
+#[allow(clippy::too_many_arguments)]
+fn line_touches_box(x1: i64, y1: i64, x2: i64, y2: i64, left: i64, bottom: i64, right: i64, top: i64) -> bool {
+    let (dx, dy) = (x2 - x1, y2 - y1);
+    // the line's parameter range inside the box, as fractions kept exact in integers
+    let (mut enter, mut leave) = ((0i64, 1i64), (1i64, 1i64));
+
+    for (p, q) in [(-dx, x1 - left), (dx, right - x1), (-dy, y1 - bottom), (dy, top - y1)] {
+        if p == 0 {
+            if q < 0 {
+                return false;
+            }
+            continue;
+        }
+        // t = q / p; normalize the denominator to be positive
+        let t = if p < 0 { (-q, -p) } else { (q, p) };
+        if p < 0 {
+            if t.0 * enter.1 > enter.0 * t.1 {
+                enter = t;
+            }
+        } else if t.0 * leave.1 < leave.0 * t.1 {
+            leave = t;
+        }
+    }
+
+    enter.0 * leave.1 <= leave.0 * enter.1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use crate::fixed::FRACUNIT;
+    use crate::wad::Wad;
+
+    const DOOM1: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/doom1.wad");
+
+    #[test]
+    fn builds_the_shipped_blockmaps() {
+        let wad = Wad::open(DOOM1).unwrap();
+        for episode_map in 1..=9 {
+            let lumps = MapLumps::find(&wad, &format!("E1M{}", episode_map)).unwrap();
+            let map = Map::from_lumps(&lumps).unwrap();
+            let shipped = Blockmap::from_lump(lumps.require("BLOCKMAP").unwrap(), &map).unwrap();
+            let built = Blockmap::build(&map);
+
+            assert_eq!((built.origin_x, built.origin_y), (shipped.origin_x, shipped.origin_y), "E1M{}", episode_map);
+            assert_eq!(built.columns, shipped.columns, "E1M{}", episode_map);
+            // id's E1M3 has an extra row of empty blocks at the top
+            assert_eq!(built.rows + usize::from(episode_map == 3), shipped.rows, "E1M{}", episode_map);
+            if episode_map == 3 {
+                assert!((0..shipped.columns as i32).all(|column| shipped.lines_in_block(column, built.rows as i32).eq([0])));
+            }
+
+            for (row, column) in (0..built.rows as i32).flat_map(|row| (0..built.columns as i32).map(move |column| (row, column))) {
+                let built_lines: BTreeSet<usize> = built.lines_in_block(column, row).collect();
+                let shipped_lines: BTreeSet<usize> = shipped.lines_in_block(column, row).collect();
+                assert!(built_lines.is_subset(&shipped_lines), "E1M{} block ({}, {})", episode_map, column, row);
+
+                // the shipped lists also have lines passing just outside a block
+                let (left, bottom) = (i64::from(column * MAPBLOCKUNITS) - 4, i64::from(row * MAPBLOCKUNITS) - 4);
+                let (right, top) = (left + i64::from(MAPBLOCKUNITS) + 8, bottom + i64::from(MAPBLOCKUNITS) + 8);
+                for &line in shipped_lines.difference(&built_lines) {
+                    let units = |vertex: usize| {
+                        let (x, y) = map.vertexes[vertex].map_units();
+                        (i64::from(x - (built.origin_x >> FRACBITS)), i64::from(y - (built.origin_y >> FRACBITS)))
+                    };
+                    let ((x1, y1), (x2, y2)) = (units(map.linedefs[line].start_vertex), units(map.linedefs[line].end_vertex));
+                    assert!(line_touches_box(x1, y1, x2, y2, left, bottom, right, top),
+                            "E1M{} block ({}, {}) line {}", episode_map, column, row, line);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_through_the_lump() {
+        let wad = Wad::open(DOOM1).unwrap();
+        let map = Map::load(&wad, "E1M1").unwrap();
+        let built = Blockmap::build(&map);
+        let loaded = Blockmap::from_lump(&built.to_lump().unwrap(), &map).unwrap();
+
+        assert_eq!((loaded.origin_x, loaded.origin_y), (built.origin_x, built.origin_y));
+        assert_eq!((loaded.columns, loaded.rows), (built.columns, built.rows));
+        assert!(loaded.lines == built.lines);
+    }
+
+    #[test]
+    fn iterators_stop_and_skip_seen_lines() {
+        let wad = Wad::open(DOOM1).unwrap();
+        let map = Map::load(&wad, "E1M1").unwrap();
+        let mut blockmap = Blockmap::build(&map);
+        let mut valid = ValidCount::new(map.linedefs.len());
+
+        // a block with a few lines, and line 0 which every block lists
+        let (column, row) = (0..blockmap.rows as i32)
+            .flat_map(|row| (0..blockmap.columns as i32).map(move |column| (column, row)))
+            .find(|&(column, row)| blockmap.lines_in_block(column, row).count() > 3)
+            .unwrap();
+        let lines: Vec<usize> = blockmap.lines_in_block(column, row).collect();
+
+        valid.next();
+        let mut seen = Vec::new();
+        assert!(blockmap.block_lines_iterator(column, row, &mut valid, |line| {
+            seen.push(line);
+            true
+        }));
+        assert_eq!(seen, lines);
+        // the same query sees nothing new, a new one sees everything again
+        assert!(blockmap.block_lines_iterator(column, row, &mut valid, |_| panic!("line seen twice")));
+        assert!(blockmap.block_lines_iterator(column + 1, row, &mut valid, |line| line != 0));
+
+        valid.next();
+        let mut seen = Vec::new();
+        assert!(!blockmap.block_lines_iterator(column, row, &mut valid, |line| {
+            seen.push(line);
+            seen.len() < 2
+        }));
+        assert_eq!(seen, lines[..2]);
+
+        let (x, y) = (blockmap.origin_x + 64 * FRACUNIT, blockmap.origin_y + 64 * FRACUNIT);
+        for thing in 0..3 {
+            assert!(blockmap.link_thing(thing, x, y));
+        }
+        assert!(!blockmap.link_thing(3, blockmap.origin_x - FRACUNIT, y));
+        blockmap.unlink_thing(1, x, y);
+
+        let mut seen = Vec::new();
+        assert!(blockmap.block_things_iterator(0, 0, |thing| {
+            seen.push(thing);
+            true
+        }));
+        assert_eq!(seen, [2, 0]);
+        assert!(!blockmap.block_things_iterator(0, 0, |thing| thing != 2));
+    }
+}
@@ -0,0 +1,416 @@
+use std::f64::consts::TAU;
+use crate::fixed::{fixed_mul, Fixed, FRACBITS};
+use crate::map::nodebuilder::NodeBuilder;
+use crate::map::{i16_at, records, u16_at, Map, MapError, MapLumps, Vertex};
+
+mod extended;
+
+/**
+ * An axis aligned box in fixed point map coordinates, stored in a NODES lump as
+ * top, bottom, left, right.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BoundingBox {
+    pub top: Fixed,
+    pub bottom: Fixed,
+    pub left: Fixed,
+    pub right: Fixed,
+}
+
+impl BoundingBox {
+    pub fn contains(&self, x: Fixed, y: Fixed) -> bool {
+        x >= self.left && x <= self.right && y >= self.bottom && y <= self.top
+    }
+}
+
+/**
+ * A child of a node: another node or, as a leaf, a subsector.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Child {
+    Node(usize),
+    Subsector(usize),
+}
+
+// Set on a child in a vanilla NODES lump when it is a subsector
+const NF_SUBSECTOR: u16 = 0x8000;
+
+/**
+ * A partition line splitting the map in two, with the bounding boxes of both halves.
+ * Index 0 of `bbox` and `children` is the right (front) side, index 1 the left (back) side.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Node {
+    pub x: Fixed,
+    pub y: Fixed,
+    pub dx: Fixed,
+    pub dy: Fixed,
+    pub bbox: [BoundingBox; 2],
+    pub children: [Child; 2],
+}
+
+impl Node {
+    /**
+     * Which side of the partition line a point is on: 0 for the front, 1 for the back.
+     * This is vanilla's `R_PointOnSide`, including its fixed point precision.
+     */
+    pub fn point_on_side(&self, x: Fixed, y: Fixed) -> usize {
+        if self.dx == 0 {
+            return if x <= self.x { (self.dy > 0) as usize } else { (self.dy < 0) as usize };
+        }
+        if self.dy == 0 {
+            return if y <= self.y { (self.dx < 0) as usize } else { (self.dx > 0) as usize };
+        }
+
+        let dx = x.wrapping_sub(self.x);
+        let dy = y.wrapping_sub(self.y);
+
+        // try to quickly decide by looking at the sign bits
+        if (self.dy ^ self.dx ^ dx ^ dy) < 0 {
+            return ((self.dy ^ dx) < 0) as usize;
+        }
+
+        let left = fixed_mul(self.dy >> FRACBITS, dx);
+        let right = fixed_mul(dy, self.dx >> FRACBITS);
+        if right < left { 0 } else { 1 }
+    }
+}
+
+/**
+ * A piece of a linedef bordering a subsector. Minisegs of GL nodes have no linedef.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Seg {
+    pub start_vertex: usize,
+    pub end_vertex: usize,
+    // binary angle, 0x40000000 is 90 degrees
+    pub angle: u32,
+    pub linedef: Option<usize>,
+    // 0 if the seg runs along the linedef's front side, 1 for the back side
+    pub side: usize,
+    // distance along the linedef from its start (or end for side 1) to the seg
+    pub offset: Fixed,
+}
+
+/**
+ * A convex leaf of the BSP tree, a run of consecutive segs.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subsector {
+    pub first_seg: usize,
+    pub seg_count: usize,
+    pub sector: usize,
+}
+
+/**
+ * The BSP tree of a map. The root is the last node; a map without nodes is a single subsector.
+ */
+pub struct Bsp {
+    // the map's vertexes followed by any vertexes the node builder added
+    pub vertexes: Vec<Vertex>,
+    pub segs: Vec<Seg>,
+    pub subsectors: Vec<Subsector>,
+    pub nodes: Vec<Node>,
+}
+
+impl Bsp {
+    /**
+     * Loads the NODES, SEGS and SSECTORS lumps of a map. Besides vanilla nodes, ZDoom's
+     * extended and GL nodes (XNOD, XGLN, XGL2, XGL3 and their compressed Z variants, also
+     * found in ZNODES) and DeePBSP nodes are recognised by their signature.
+     */
+    pub fn from_lumps(lumps: &MapLumps, map: &Map) -> Result<Bsp, MapError> {
+        if let Some(bsp) = extended::from_lumps(lumps, map) {
+            return bsp;
+        }
+
+        let child = |raw: u16| if raw & NF_SUBSECTOR != 0 {
+            Child::Subsector((raw & !NF_SUBSECTOR).into())
+        } else {
+            Child::Node(raw.into())
+        };
+        let bbox = |r: &[u8], offset: usize| BoundingBox {
+            top: i32::from(i16_at(r, offset)) << FRACBITS,
+            bottom: i32::from(i16_at(r, offset + 2)) << FRACBITS,
+            left: i32::from(i16_at(r, offset + 4)) << FRACBITS,
+            right: i32::from(i16_at(r, offset + 6)) << FRACBITS,
+        };
+
+        let nodes = records(lumps.require("NODES")?, 28, |r| Node {
+            x: i32::from(i16_at(r, 0)) << FRACBITS,
+            y: i32::from(i16_at(r, 2)) << FRACBITS,
+            dx: i32::from(i16_at(r, 4)) << FRACBITS,
+            dy: i32::from(i16_at(r, 6)) << FRACBITS,
+            bbox: [bbox(r, 8), bbox(r, 16)],
+            children: [child(u16_at(r, 24)), child(u16_at(r, 26))],
+        });
+
+        let segs = records(lumps.require("SEGS")?, 12, |r| Seg {
+            start_vertex: u16_at(r, 0).into(),
+            end_vertex: u16_at(r, 2).into(),
+            angle: u32::from(u16_at(r, 4)) << 16,
+            linedef: Some(u16_at(r, 6).into()),
+            side: usize::from(u16_at(r, 8) != 0),
+            offset: i32::from(i16_at(r, 10)) << FRACBITS,
+        });
+
+        let subsectors = records(lumps.require("SSECTORS")?, 4, |r| Subsector {
+            seg_count: u16_at(r, 0).into(),
+            first_seg: u16_at(r, 2).into(),
+            sector: 0,
+        });
+
+        Bsp::new(map, map.vertexes.clone(), segs, subsectors, nodes)
+    }
+
+    /**
+     * Loads the map's nodes, or builds them when the lumps are missing or broken.
+     */
+    pub fn load_or_build(lumps: &MapLumps, map: &Map) -> Result<Bsp, MapError> {
+        match Bsp::from_lumps(lumps, map) {
+            Err(MapError::MissingLump { .. } | MapError::BadBsp { .. }) => NodeBuilder::default().build(map),
+            result => result,
+        }
+    }
+
+    /**
+     * Checks a tree for consistency with its map and works out the sector of every subsector.
+     */
+    pub fn new(map: &Map, vertexes: Vec<Vertex>, segs: Vec<Seg>, mut subsectors: Vec<Subsector>, nodes: Vec<Node>)
+        -> Result<Bsp, MapError> {
+        let bad = |lump: &'static str, index: usize, problem: &'static str| MapError::BadBsp { lump, index, problem };
+
+        for (index, seg) in segs.iter().enumerate() {
+            if seg.start_vertex >= vertexes.len() || seg.end_vertex >= vertexes.len() {
+                return Err(bad("SEGS", index, "vertex out of range"));
+            }
+            if let Some(linedef) = seg.linedef {
+                if map.side_sector(linedef, seg.side).is_none() {
+                    return Err(bad("SEGS", index, "linedef or side out of range"));
+                }
+            }
+        }
+
+        for (index, subsector) in subsectors.iter_mut().enumerate() {
+            let range = subsector.first_seg..subsector.first_seg + subsector.seg_count;
+            if range.is_empty() || range.end > segs.len() {
+                return Err(bad("SSECTORS", index, "seg range out of bounds"));
+            }
+            subsector.sector = segs[range].iter()
+                .find_map(|seg| map.side_sector(seg.linedef?, seg.side))
+                .ok_or(bad("SSECTORS", index, "no seg on a linedef"))?;
+        }
+        if subsectors.is_empty() {
+            return Err(bad("SSECTORS", 0, "no subsectors"));
+        }
+
+        // every node has to be reached exactly once from the root
+        let mut visited = vec![false; nodes.len()];
+        let mut pending: Vec<usize> = nodes.len().checked_sub(1).into_iter().collect();
+        while let Some(index) = pending.pop() {
+            if std::mem::replace(&mut visited[index], true) {
+                return Err(bad("NODES", index, "node is part of a cycle"));
+            }
+            for child in nodes[index].children {
+                match child {
+                    Child::Node(node) if node >= nodes.len() => return Err(bad("NODES", index, "child node out of range")),
+                    Child::Node(node) => pending.push(node),
+                    Child::Subsector(subsector) if subsector >= subsectors.len() =>
+                        return Err(bad("NODES", index, "child subsector out of range")),
+                    Child::Subsector(_) => {}
+                }
+            }
+        }
+        if let Some(index) = visited.iter().position(|&visited| !visited) {
+            return Err(bad("NODES", index, "node is not reachable from the root"));
+        }
+
+        Ok(Bsp { vertexes, segs, subsectors, nodes })
+    }
+
+    /**
+     * The root of the tree.
+     */
+    pub fn root(&self) -> Child {
+        match self.nodes.len() {
+            0 => Child::Subsector(0),
+            len => Child::Node(len - 1),
+        }
+    }
+
+    /**
+     * The subsector containing a point, vanilla's `R_PointInSubsector`.
+     */
+    pub fn subsector_at(&self, x: Fixed, y: Fixed) -> usize {
+        let mut child = self.root();
+        loop {
+            match child {
+                Child::Subsector(subsector) => return subsector,
+                Child::Node(node) => {
+                    let node = &self.nodes[node];
+                    child = node.children[node.point_on_side(x, y)];
+                }
+            }
+        }
+    }
+
+    /**
+     * The sector containing a point.
+     */
+    pub fn sector_at(&self, x: Fixed, y: Fixed) -> usize {
+        self.subsectors[self.subsector_at(x, y)].sector
+    }
+
+    /**
+     * The segs of a subsector.
+     */
+    pub fn subsector_segs(&self, subsector: usize) -> &[Seg] {
+        let subsector = &self.subsectors[subsector];
+        &self.segs[subsector.first_seg..subsector.first_seg + subsector.seg_count]
+    }
+
+    /**
+     * Visits subsectors front to back as seen from a viewpoint, like `R_RenderBSPNode`. The
+     * side of each node facing the viewer is always descended into; the far side only when
+     * `visible` accepts its bounding box. `visit` returns false to stop the traversal.
+     */
+    pub fn front_to_back(&self, x: Fixed, y: Fixed,
+                         mut visible: impl FnMut(&BoundingBox) -> bool,
+                         mut visit: impl FnMut(usize) -> bool) {
+        // the far side of a node is only checked once its near side has been visited, so
+        // `visible` can take what was already drawn into account
+        enum Pending {
+            Visit(Child),
+            FarSide(usize, usize),
+        }
+
+        let mut pending = vec![Pending::Visit(self.root())];
+        while let Some(next) = pending.pop() {
+            match next {
+                Pending::Visit(Child::Subsector(subsector)) => {
+                    if !visit(subsector) {
+                        return;
+                    }
+                }
+                Pending::Visit(Child::Node(node)) => {
+                    let side = self.nodes[node].point_on_side(x, y);
+                    pending.push(Pending::FarSide(node, side ^ 1));
+                    pending.push(Pending::Visit(self.nodes[node].children[side]));
+                }
+                Pending::FarSide(node, side) => {
+                    if visible(&self.nodes[node].bbox[side]) {
+                        pending.push(Pending::Visit(self.nodes[node].children[side]));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/**
+ * The binary angle of a direction, as vanilla stores it in segs.
+ */
+pub(crate) fn bam_angle(dx: f64, dy: f64) -> u32 {
+    (dy.atan2(dx) / TAU * 4294967296.0).rem_euclid(4294967296.0) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixed::FRACUNIT;
+    use crate::wad::Wad;
+
+    fn e1m1() -> (Map, Bsp) {
+        let wad = Wad::open(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/doom1.wad")).unwrap();
+        let lumps = MapLumps::find(&wad, "E1M1").unwrap();
+        let map = Map::from_lumps(&lumps).unwrap();
+        let bsp = Bsp::from_lumps(&lumps, &map).unwrap();
+        (map, bsp)
+    }
+
+    fn node(x: i32, y: i32, dx: i32, dy: i32) -> Node {
+        let bbox = BoundingBox { top: 0, bottom: 0, left: 0, right: 0 };
+        Node {
+            x: x * FRACUNIT,
+            y: y * FRACUNIT,
+            dx: dx * FRACUNIT,
+            dy: dy * FRACUNIT,
+            bbox: [bbox; 2],
+            children: [Child::Subsector(0), Child::Subsector(1)],
+        }
+    }
+
+    #[test]
+    fn tells_the_sides_of_partition_lines() {
+        let side = |node: Node, x: i32, y: i32| node.point_on_side(x * FRACUNIT, y * FRACUNIT);
+        // the front is on the right, looking along the line
+        assert_eq!(side(node(0, 0, 0, 64), 8, 0), 0);
+        assert_eq!(side(node(0, 0, 0, 64), -8, 0), 1);
+        assert_eq!(side(node(0, 0, 0, -64), 8, 0), 1);
+        assert_eq!(side(node(0, 0, 64, 0), 0, -8), 0);
+        assert_eq!(side(node(0, 0, -64, 0), 0, -8), 1);
+        assert_eq!(side(node(0, 0, 64, 64), 16, 0), 0);
+        assert_eq!(side(node(0, 0, 64, 64), 0, 16), 1);
+        assert_eq!(side(node(0, 0, -64, 64), 16, 8), 0);
+        assert_eq!(side(node(0, 0, -64, 64), -16, -8), 1);
+        // points on the line count as the back, like in vanilla
+        assert_eq!(side(node(0, 0, 64, 64), 32, 32), 1);
+        assert_eq!(side(node(0, 0, 0, 64), 0, 32), 1);
+    }
+
+    #[test]
+    fn finds_the_subsector_of_a_point() {
+        let (_, bsp) = e1m1();
+        for subsector in 0..bsp.subsectors.len() {
+            // subsectors are convex, so the average of their seg ends lies inside
+            let segs = bsp.subsector_segs(subsector);
+            let ends = segs.iter().flat_map(|seg| [bsp.vertexes[seg.start_vertex], bsp.vertexes[seg.end_vertex]]);
+            let (x, y) = ends.fold((0i64, 0i64), |(x, y), vertex| (x + i64::from(vertex.x), y + i64::from(vertex.y)));
+            let count = 2 * segs.len() as i64;
+            if segs.len() > 2 {
+                assert_eq!(bsp.subsector_at((x / count) as Fixed, (y / count) as Fixed), subsector);
+            }
+        }
+    }
+
+    #[test]
+    fn visits_subsectors_front_to_back() {
+        let (map, bsp) = e1m1();
+        let start = map.things.iter().find(|thing| thing.thing_type == 1).unwrap();
+
+        let mut visited = Vec::new();
+        bsp.front_to_back(start.x, start.y, |_| true, |subsector| {
+            visited.push(subsector);
+            true
+        });
+        assert_eq!(visited[0], bsp.subsector_at(start.x, start.y));
+        visited.sort_unstable();
+        assert!(visited.iter().copied().eq(0..bsp.subsectors.len()));
+
+        let mut count = 0;
+        bsp.front_to_back(start.x, start.y, |_| true, |_| {
+            count += 1;
+            count < 10
+        });
+        assert_eq!(count, 10);
+
+        // without far sides only the path down to the viewer's subsector is left
+        let mut visited = Vec::new();
+        bsp.front_to_back(start.x, start.y, |_| false, |subsector| {
+            visited.push(subsector);
+            true
+        });
+        assert_eq!(visited, [bsp.subsector_at(start.x, start.y)]);
+    }
+
+    #[test]
+    fn rejects_unreachable_nodes() {
+        let (map, bsp) = e1m1();
+        let mut nodes = bsp.nodes.clone();
+        let root = nodes.len() - 1;
+        nodes.insert(root, nodes[0]);
+        let result = Bsp::new(&map, bsp.vertexes, bsp.segs, bsp.subsectors, nodes);
+        assert!(matches!(result, Err(MapError::BadBsp { lump: "NODES", index, problem: "node is not reachable from the root" })
+                         if index == root));
+    }
+}
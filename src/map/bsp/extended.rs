@@ -0,0 +1,422 @@
+use miniz_oxide::inflate::decompress_to_vec_zlib;
+use crate::fixed::{Fixed, FRACBITS, FRACUNIT};
+use crate::map::bsp::{bam_angle, BoundingBox, Bsp, Child, Node, Seg, Subsector};
+use crate::map::{i16_at, records, u16_at, Map, MapError, MapLumps, Vertex};
+
+// DeePBSP marks its NODES lump with this header; SEGS and SSECTORS carry none
+const DEEPBSP_SIGNATURE: &[u8; 8] = b"xNd4\0\0\0\0";
+
+/**
+ * The extended node formats of ZDoom's node builder, named by their signature. A Z in place
+ * of the X means the data after the signature is zlib compressed.
+ */
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    // normal nodes, 32 bit vertex indices
+    Xnod,
+    // GL nodes with minisegs, seg end points implied by the next seg
+    Xgln,
+    // XGLN with 32 bit linedef indices
+    Xgl2,
+    // XGL2 with fixed point partition lines
+    Xgl3,
+}
+
+impl Format {
+    fn detect(lump: &[u8]) -> Option<(Format, bool)> {
+        let signature = lump.get(..4)?;
+        let format = match &signature[1..] {
+            b"NOD" => Format::Xnod,
+            b"GLN" => Format::Xgln,
+            b"GL2" => Format::Xgl2,
+            b"GL3" => Format::Xgl3,
+            _ => return None,
+        };
+        match signature[0] {
+            b'X' => Some((format, false)),
+            b'Z' => Some((format, true)),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Loads nodes in one of the extended formats, if the map has them: ZDoom's in NODES,
+ * SSECTORS or ZNODES, or DeePBSP's in NODES, SEGS and SSECTORS.
+ */
+pub(super) fn from_lumps(lumps: &MapLumps, map: &Map) -> Option<Result<Bsp, MapError>> {
+    for name in ["NODES", "SSECTORS", "ZNODES"] {
+        let Some(lump) = lumps.get(name) else { continue };
+        if let Some((format, compressed)) = Format::detect(lump) {
+            return Some(load_zdoom(lump, format, compressed, map));
+        }
+    }
+
+    let nodes = lumps.get("NODES")?;
+    if nodes.starts_with(DEEPBSP_SIGNATURE) {
+        return Some(load_deepbsp(nodes, lumps, map));
+    }
+    None
+}
+
+fn load_zdoom(lump: &[u8], format: Format, compressed: bool, map: &Map) -> Result<Bsp, MapError> {
+    let inflated;
+    let data = if compressed {
+        inflated = decompress_to_vec_zlib(&lump[4..])
+            .map_err(|_| MapError::BadBsp { lump: "NODES", index: 0, problem: "compressed nodes could not be inflated" })?;
+        &inflated[..]
+    } else {
+        &lump[4..]
+    };
+    let mut reader = Reader { data, offset: 0 };
+
+    // indices below the original vertex count refer to VERTEXES, the rest to the new ones
+    let original = reader.u32()? as usize;
+    if original > map.vertexes.len() {
+        return Err(MapError::BadBsp { lump: "NODES", index: 0, problem: "more original vertexes than the map has" });
+    }
+    let added = reader.u32()? as usize;
+    let mut vertexes = map.vertexes.clone();
+    for _ in 0..added {
+        vertexes.push(Vertex { x: reader.i32()?, y: reader.i32()? });
+    }
+    let vertex = |index: u32| {
+        let index = index as usize;
+        if index < original { index } else { index - original + map.vertexes.len() }
+    };
+
+    let subsector_count = reader.u32()? as usize;
+    let mut subsectors = Vec::with_capacity(subsector_count.min(data.len()));
+    let mut first_seg = 0;
+    for _ in 0..subsector_count {
+        let seg_count = reader.u32()? as usize;
+        subsectors.push(Subsector { first_seg, seg_count, sector: 0 });
+        first_seg += seg_count;
+    }
+
+    let seg_count = reader.u32()? as usize;
+    let mut segs = Vec::with_capacity(seg_count.min(data.len()));
+    for _ in 0..seg_count {
+        let start_vertex = vertex(reader.u32()?);
+        // the end vertex of a normal seg, the partner seg of a GL one
+        let second = reader.u32()?;
+        let linedef = match format {
+            Format::Xnod | Format::Xgln => Some(reader.u16()?).filter(|&line| line != 0xffff).map(usize::from),
+            Format::Xgl2 | Format::Xgl3 => Some(reader.u32()?).filter(|&line| line != 0xffff_ffff).map(|line| line as usize),
+        };
+        let side = usize::from(reader.u8()? != 0);
+        let end_vertex = if format == Format::Xnod { vertex(second) } else { 0 };
+        segs.push(Seg { start_vertex, end_vertex, angle: 0, linedef, side, offset: 0 });
+    }
+    if first_seg != segs.len() {
+        return Err(MapError::BadBsp { lump: "SSECTORS", index: 0, problem: "subsectors do not cover all segs" });
+    }
+
+    // GL segs go round their subsector, each ending where the next one starts
+    if format != Format::Xnod {
+        for subsector in &subsectors {
+            let range = subsector.first_seg..subsector.first_seg + subsector.seg_count;
+            for index in range.clone() {
+                let next = if index + 1 < range.end { index + 1 } else { range.start };
+                segs[index].end_vertex = segs[next].start_vertex;
+            }
+        }
+    }
+
+    let node_count = reader.u32()? as usize;
+    let mut nodes = Vec::with_capacity(node_count.min(data.len()));
+    for _ in 0..node_count {
+        let [x, y, dx, dy] = if format == Format::Xgl3 {
+            [reader.i32()?, reader.i32()?, reader.i32()?, reader.i32()?]
+        } else {
+            [reader.i16()?, reader.i16()?, reader.i16()?, reader.i16()?]
+        };
+        let mut bbox = [BoundingBox { top: 0, bottom: 0, left: 0, right: 0 }; 2];
+        for side in &mut bbox {
+            *side = BoundingBox { top: reader.i16()?, bottom: reader.i16()?, left: reader.i16()?, right: reader.i16()? };
+        }
+        let children = [child(reader.u32()?), child(reader.u32()?)];
+        nodes.push(Node { x, y, dx, dy, bbox, children });
+    }
+
+    fill_seg_geometry(&mut segs, &vertexes, map);
+    Bsp::new(map, vertexes, segs, subsectors, nodes)
+}
+
+fn load_deepbsp(nodes: &[u8], lumps: &MapLumps, map: &Map) -> Result<Bsp, MapError> {
+    let unit = |r: &[u8], offset: usize| Fixed::from(i16_at(r, offset)) << FRACBITS;
+    let u32_at = |r: &[u8], offset: usize| u32::from_le_bytes(r[offset..offset + 4].try_into().unwrap());
+    let bbox = |r: &[u8], offset: usize| BoundingBox {
+        top: unit(r, offset),
+        bottom: unit(r, offset + 2),
+        left: unit(r, offset + 4),
+        right: unit(r, offset + 6),
+    };
+
+    let nodes = records(&nodes[DEEPBSP_SIGNATURE.len()..], 32, |r| Node {
+        x: unit(r, 0),
+        y: unit(r, 2),
+        dx: unit(r, 4),
+        dy: unit(r, 6),
+        bbox: [bbox(r, 8), bbox(r, 16)],
+        children: [child(u32_at(r, 24)), child(u32_at(r, 28))],
+    });
+
+    let segs = records(lumps.require("SEGS")?, 16, |r| Seg {
+        start_vertex: u32_at(r, 0) as usize,
+        end_vertex: u32_at(r, 4) as usize,
+        angle: u32::from(u16_at(r, 8)) << 16,
+        linedef: Some(u16_at(r, 10).into()),
+        side: usize::from(u16_at(r, 12) != 0),
+        offset: unit(r, 14),
+    });
+
+    let subsectors = records(lumps.require("SSECTORS")?, 6, |r| Subsector {
+        seg_count: u16_at(r, 0).into(),
+        first_seg: u32_at(r, 2) as usize,
+        sector: 0,
+    });
+
+    Bsp::new(map, map.vertexes.clone(), segs, subsectors, nodes)
+}
+
+fn child(raw: u32) -> Child {
+    if raw & 0x8000_0000 != 0 {
+        Child::Subsector((raw & 0x7fff_ffff) as usize)
+    } else {
+        Child::Node(raw as usize)
+    }
+}
+
+/**
+ * Works out the angle and offset the extended formats leave out.
+ */
+fn fill_seg_geometry(segs: &mut [Seg], vertexes: &[Vertex], map: &Map) {
+    let position = |index: usize| vertexes.get(index)
+        .map(|v| (f64::from(v.x) / f64::from(FRACUNIT), f64::from(v.y) / f64::from(FRACUNIT)));
+
+    for seg in segs {
+        let (Some((x1, y1)), Some((x2, y2))) = (position(seg.start_vertex), position(seg.end_vertex)) else { continue };
+        seg.angle = bam_angle(x2 - x1, y2 - y1);
+
+        let Some(line) = seg.linedef.and_then(|line| map.linedefs.get(line)) else { continue };
+        let origin = if seg.side == 0 { line.start_vertex } else { line.end_vertex };
+        if let Some((x, y)) = position(origin) {
+            seg.offset = ((x1 - x).hypot(y1 - y) * f64::from(FRACUNIT)).round() as Fixed;
+        }
+    }
+}
+
+// Reads the little endian values of an extended nodes lump in sequence
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], MapError> {
+        let bytes = self.data.get(self.offset..self.offset + N)
+            .ok_or(MapError::BadBsp { lump: "NODES", index: self.offset, problem: "extended nodes are truncated" })?;
+        self.offset += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, MapError> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, MapError> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    // a whole map unit value, returned in fixed point
+    fn i16(&mut self) -> Result<Fixed, MapError> {
+        self.bytes().map(|bytes| Fixed::from(i16::from_le_bytes(bytes)) << FRACBITS)
+    }
+
+    fn u32(&mut self) -> Result<u32, MapError> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32, MapError> {
+        self.bytes().map(i32::from_le_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miniz_oxide::deflate::compress_to_vec_zlib;
+    use crate::wad::{Identification, Wad, WadWriter};
+
+    // A 128 unit square room split down the middle at x = 64, adding vertexes 4 at (64, 0) and
+    // 5 at (64, 128). Subsector 0 is the right half, subsector 1 the left one.
+    const VERTEXES: [(i16, i16); 6] = [(0, 0), (0, 128), (128, 128), (128, 0), (64, 0), (64, 128)];
+
+    // start and end vertex and linedef of the segs, clockwise round each subsector
+    const SEGS: [(u32, u32, Option<u16>); 8] = [
+        (5, 2, Some(1)), (2, 3, Some(2)), (3, 4, Some(3)), (4, 5, None),
+        (4, 0, Some(3)), (0, 1, Some(0)), (1, 5, Some(1)), (5, 4, None),
+    ];
+
+    // the room with the first `vertexes` of VERTEXES and `node_lumps` after its other lumps
+    fn wad(vertexes: usize, node_lumps: &[(&str, Vec<u8>)]) -> Wad {
+        let mut writer = WadWriter::new(Identification::PWAD);
+        let mut linedefs = Vec::new();
+        for line in 0..4u16 {
+            for value in [line, (line + 1) % 4, 0, 0, 0, 0, 0xffff] {
+                linedefs.extend(value.to_le_bytes());
+            }
+        }
+        let mut sidedef = vec![0; 4];
+        sidedef.extend(b"-\0\0\0\0\0\0\0-\0\0\0\0\0\0\0STARTAN3");
+        sidedef.extend(0u16.to_le_bytes());
+        let mut sector = vec![0, 0, 128, 0];
+        sector.extend(b"FLOOR4_8CEIL3_5\0");
+        sector.extend([160, 0, 0, 0, 0, 0]);
+        let vertex_lump: Vec<u8> = VERTEXES[..vertexes].iter()
+            .flat_map(|&(x, y)| [x.to_le_bytes(), y.to_le_bytes()].concat())
+            .collect();
+
+        writer.add_marker("MAP01")
+            .add_lump("THINGS", Vec::new())
+            .add_lump("LINEDEFS", linedefs)
+            .add_lump("SIDEDEFS", sidedef)
+            .add_lump("VERTEXES", vertex_lump)
+            .add_lump("SECTORS", sector);
+        for (name, data) in node_lumps {
+            writer.add_lump(*name, data.clone());
+        }
+        Wad::from_bytes(writer.to_bytes()).unwrap()
+    }
+
+    // the parts of a ZDoom nodes lump after its signature
+    fn zdoom_nodes(format: Format) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(4u32.to_le_bytes());
+        data.extend(2u32.to_le_bytes());
+        for &(x, y) in &VERTEXES[4..] {
+            data.extend((i32::from(x) << FRACBITS).to_le_bytes());
+            data.extend((i32::from(y) << FRACBITS).to_le_bytes());
+        }
+
+        let gl = format != Format::Xnod;
+        let segs: Vec<_> = SEGS.iter().filter(|seg| gl || seg.2.is_some()).collect();
+        data.extend(2u32.to_le_bytes());
+        for _ in 0..2 {
+            data.extend((segs.len() as u32 / 2).to_le_bytes());
+        }
+        data.extend((segs.len() as u32).to_le_bytes());
+        for &&(start, end, line) in &segs {
+            data.extend(start.to_le_bytes());
+            data.extend(if gl { u32::MAX } else { end }.to_le_bytes());
+            match format {
+                Format::Xnod | Format::Xgln => data.extend(line.unwrap_or(0xffff).to_le_bytes()),
+                Format::Xgl2 | Format::Xgl3 => data.extend(line.map_or(u32::MAX, u32::from).to_le_bytes()),
+            }
+            data.push(0);
+        }
+
+        data.extend(1u32.to_le_bytes());
+        for value in [64i16, 0, 0, 128] {
+            if format == Format::Xgl3 {
+                data.extend((i32::from(value) << FRACBITS).to_le_bytes());
+            } else {
+                data.extend(value.to_le_bytes());
+            }
+        }
+        for value in [128i16, 0, 64, 128, 128, 0, 0, 64] {
+            data.extend(value.to_le_bytes());
+        }
+        data.extend(0x8000_0000u32.to_le_bytes());
+        data.extend(0x8000_0001u32.to_le_bytes());
+        data
+    }
+
+    fn load(wad: &Wad) -> Bsp {
+        let lumps = MapLumps::find(wad, "MAP01").unwrap();
+        let map = Map::from_lumps(&lumps).unwrap();
+        from_lumps(&lumps, &map).expect("extended nodes not recognised").unwrap()
+    }
+
+    fn check(bsp: &Bsp, gl: bool) {
+        assert_eq!(bsp.vertexes.len(), 6);
+        assert_eq!(bsp.vertexes[5], Vertex { x: 64 << FRACBITS, y: 128 << FRACBITS });
+
+        let segs: Vec<(usize, usize, Option<usize>)> = bsp.segs.iter()
+            .map(|seg| (seg.start_vertex, seg.end_vertex, seg.linedef))
+            .collect();
+        let expected: Vec<(usize, usize, Option<usize>)> = SEGS.iter()
+            .filter(|seg| gl || seg.2.is_some())
+            .map(|&(start, end, line)| (start as usize, end as usize, line.map(usize::from)))
+            .collect();
+        assert_eq!(segs, expected);
+
+        // the seg from (64, 128) to (128, 128) along linedef 1 heads east, halfway along it
+        assert_eq!((bsp.segs[0].angle, bsp.segs[0].offset), (0, 64 << FRACBITS));
+        assert_eq!(bsp.subsector_at(96 << FRACBITS, 64 << FRACBITS), 0);
+        assert_eq!(bsp.subsector_at(32 << FRACBITS, 64 << FRACBITS), 1);
+    }
+
+    #[test]
+    fn loads_zdoom_nodes() {
+        for (signature, format) in [(b"NOD", Format::Xnod), (b"GLN", Format::Xgln), (b"GL2", Format::Xgl2), (b"GL3", Format::Xgl3)] {
+            let data = zdoom_nodes(format);
+            let plain = [&[b'X'][..], signature, &data].concat();
+            let compressed = [&[b'Z'][..], signature, &compress_to_vec_zlib(&data, 6)].concat();
+
+            check(&load(&wad(4, &[("NODES", plain)])), format != Format::Xnod);
+            check(&load(&wad(4, &[("ZNODES", compressed)])), format != Format::Xnod);
+        }
+    }
+
+    #[test]
+    fn rejects_broken_zdoom_nodes() {
+        let data = zdoom_nodes(Format::Xnod);
+        let truncated = [&b"XNOD"[..], &data[..data.len() - 1]].concat();
+        let lumps = wad(4, &[("NODES", truncated)]);
+        let lumps = MapLumps::find(&lumps, "MAP01").unwrap();
+        let map = Map::from_lumps(&lumps).unwrap();
+        assert!(matches!(from_lumps(&lumps, &map), Some(Err(MapError::BadBsp { problem: "extended nodes are truncated", .. }))));
+
+        let garbage = [&b"ZNOD"[..], &data].concat();
+        let lumps = wad(4, &[("NODES", garbage)]);
+        let lumps = MapLumps::find(&lumps, "MAP01").unwrap();
+        let map = Map::from_lumps(&lumps).unwrap();
+        assert!(matches!(from_lumps(&lumps, &map), Some(Err(MapError::BadBsp { problem: "compressed nodes could not be inflated", .. }))));
+    }
+
+    #[test]
+    fn loads_deepbsp_nodes() {
+        let mut segs = Vec::new();
+        for &(start, end, line) in SEGS.iter().filter(|seg| seg.2.is_some()) {
+            let line = line.unwrap();
+            // DeePBSP stores angle and offset like vanilla
+            let (x1, y1) = VERTEXES[start as usize];
+            let (x2, y2) = VERTEXES[end as usize];
+            let angle = (bam_angle(f64::from(x2 - x1), f64::from(y2 - y1)) >> 16) as u16;
+            let (ox, oy) = VERTEXES[usize::from(line)];
+            let offset = (x1 - ox).abs().max((y1 - oy).abs());
+            for value in [start, end] {
+                segs.extend(value.to_le_bytes());
+            }
+            for value in [angle, line, 0, offset as u16] {
+                segs.extend(value.to_le_bytes());
+            }
+        }
+        let mut ssectors = Vec::new();
+        for first in [0u32, 3] {
+            ssectors.extend(3u16.to_le_bytes());
+            ssectors.extend(first.to_le_bytes());
+        }
+        let mut nodes = DEEPBSP_SIGNATURE.to_vec();
+        for value in [64i16, 0, 0, 128, 128, 0, 64, 128, 128, 0, 0, 64] {
+            nodes.extend(value.to_le_bytes());
+        }
+        nodes.extend(0x8000_0000u32.to_le_bytes());
+        nodes.extend(0x8000_0001u32.to_le_bytes());
+
+        let wad = wad(6, &[("SEGS", segs), ("SSECTORS", ssectors), ("NODES", nodes)]);
+        check(&load(&wad), false);
+    }
+}
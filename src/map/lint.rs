@@ -0,0 +1,286 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use crate::graphics::texture_names;
+use crate::map::bsp::Bsp;
+use crate::map::{Map, MapLumps};
+use crate::resource::{Namespace, ResourceStack};
+use crate::wad::LumpName;
+
+// Vanilla stores indices in signed 16 bit fields
+const VANILLA_INDEX_LIMIT: usize = 0x7fff;
+
+// Vanilla blockmap offsets are signed 16 bit words as well
+const VANILLA_BLOCKMAP_WORDS: usize = 0x8000;
+
+/**
+ * A problem found in a map. Positions are in whole map units, where the problem can be
+ * pinned to one.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    // the lines facing a sector do not form closed loops; `at` is a vertex with a loose end
+    UnclosedSector { sector: usize, at: (i32, i32) },
+    NoFrontSide { linedef: usize, at: (i32, i32) },
+    ZeroLengthLinedef { linedef: usize, at: (i32, i32) },
+    UnknownTexture { sidedef: usize, texture: LumpName, at: Option<(i32, i32)> },
+    UnknownFlat { sector: usize, flat: LumpName, at: Option<(i32, i32)> },
+    FloorAboveCeiling { sector: usize, at: Option<(i32, i32)> },
+    NoPlayer1Start,
+    // TEXTURE1 or TEXTURE2 cannot be read, so textures are not checked
+    BadTextureLump(String),
+    // more elements than vanilla can address
+    LimitExceeded { what: &'static str, count: usize, limit: usize },
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let at = |at: &Option<(i32, i32)>| at.map(|(x, y)| format!(" at ({}, {})", x, y)).unwrap_or_default();
+        match self {
+            Problem::UnclosedSector { sector, at: (x, y) } =>
+                write!(f, "sector {} is not closed at ({}, {})", sector, x, y),
+            Problem::NoFrontSide { linedef, at: (x, y) } =>
+                write!(f, "linedef {} at ({}, {}) has no front side", linedef, x, y),
+            Problem::ZeroLengthLinedef { linedef, at: (x, y) } =>
+                write!(f, "linedef {} at ({}, {}) has zero length", linedef, x, y),
+            Problem::UnknownTexture { sidedef, texture, at: position } =>
+                write!(f, "sidedef {}{} uses unknown texture {}", sidedef, at(position), texture),
+            Problem::UnknownFlat { sector, flat, at: position } =>
+                write!(f, "sector {}{} uses unknown flat {}", sector, at(position), flat),
+            Problem::FloorAboveCeiling { sector, at: position } =>
+                write!(f, "sector {}{} has its floor above its ceiling", sector, at(position)),
+            Problem::NoPlayer1Start => write!(f, "there is no player 1 start"),
+            Problem::BadTextureLump(problem) => write!(f, "textures not checked: {}", problem),
+            Problem::LimitExceeded { what, count, limit } =>
+                write!(f, "{} {} exceed the vanilla limit of {}", count, what, limit),
+        }
+    }
+}
+
+/**
+ * Checks a map for problems that make it fail or misbehave in vanilla-like engines.
+ * Textures are looked up in TEXTURE1 and TEXTURE2 and flats in the flats namespace of
+ * `resources`, which should hold the IWAD and any PWADs the map is played with.
+ */
+pub fn lint(map: &Map, lumps: &MapLumps, resources: &ResourceStack) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let position = |vertex: usize| map.vertexes[vertex].map_units();
+
+    // the first linedef using each sidedef, and the first vertex of each sector
+    let mut sidedef_lines = vec![None; map.sidedefs.len()];
+    let mut sector_positions = vec![None; map.sectors.len()];
+    for (index, line) in map.linedefs.iter().enumerate() {
+        for sidedef in [line.front_sidedef, line.back_sidedef].into_iter().flatten() {
+            sidedef_lines[sidedef].get_or_insert(index);
+            sector_positions[map.sidedefs[sidedef].sector].get_or_insert(position(line.start_vertex));
+        }
+    }
+    let sidedef_position = |sidedef: usize| sidedef_lines[sidedef].map(|line: usize| position(map.linedefs[line].start_vertex));
+
+    for (index, line) in map.linedefs.iter().enumerate() {
+        let at = position(line.start_vertex);
+        if line.front_sidedef.is_none() {
+            problems.push(Problem::NoFrontSide { linedef: index, at });
+        }
+        if map.vertexes[line.start_vertex] == map.vertexes[line.end_vertex] {
+            problems.push(Problem::ZeroLengthLinedef { linedef: index, at });
+        }
+    }
+
+    problems.extend(unclosed_sectors(map));
+
+    match texture_names(resources) {
+        Ok(textures) => for (index, side) in map.sidedefs.iter().enumerate() {
+            let mut reported = HashSet::new();
+            for texture in [side.upper_texture, side.lower_texture, side.middle_texture] {
+                if texture != "-" && !texture.is_empty() && !textures.contains(&texture) && reported.insert(texture) {
+                    problems.push(Problem::UnknownTexture { sidedef: index, texture, at: sidedef_position(index) });
+                }
+            }
+        },
+        Err(error) => problems.push(Problem::BadTextureLump(error.to_string())),
+    }
+
+    let flats: HashSet<LumpName> = resources.namespace(Namespace::Flats).iter()
+        .filter_map(|&num| resources.lump_name(num))
+        .collect();
+    for (index, sector) in map.sectors.iter().enumerate() {
+        let at = sector_positions[index];
+        for flat in [sector.floor_texture, sector.ceiling_texture] {
+            if !flats.contains(&flat) {
+                problems.push(Problem::UnknownFlat { sector: index, flat, at });
+            }
+            if sector.floor_texture == sector.ceiling_texture {
+                break;
+            }
+        }
+        if sector.floor_height > sector.ceiling_height {
+            problems.push(Problem::FloorAboveCeiling { sector: index, at });
+        }
+    }
+
+    if !map.things.iter().any(|thing| thing.thing_type == 1) {
+        problems.push(Problem::NoPlayer1Start);
+    }
+
+    let mut limit = |what, count| if count > VANILLA_INDEX_LIMIT {
+        problems.push(Problem::LimitExceeded { what, count, limit: VANILLA_INDEX_LIMIT });
+    };
+    limit("linedefs", map.linedefs.len());
+    limit("sidedefs", map.sidedefs.len());
+    limit("sectors", map.sectors.len());
+    match Bsp::from_lumps(lumps, map) {
+        Ok(bsp) => {
+            limit("vertexes", bsp.vertexes.len());
+            limit("segs", bsp.segs.len());
+            limit("subsectors", bsp.subsectors.len());
+            limit("nodes", bsp.nodes.len());
+        }
+        Err(_) => limit("vertexes", map.vertexes.len()),
+    }
+    if let Some(blockmap) = lumps.get("BLOCKMAP") {
+        if blockmap.len() / 2 > VANILLA_BLOCKMAP_WORDS {
+            problems.push(Problem::LimitExceeded {
+                what: "blockmap words",
+                count: blockmap.len() / 2,
+                limit: VANILLA_BLOCKMAP_WORDS,
+            });
+        }
+    }
+
+    problems
+}
+
+/**
+ * Sectors whose lines do not form closed loops. Walking every line with its sector on the
+ * right, each vertex has to be entered as often as it is left.
+ */
+fn unclosed_sectors(map: &Map) -> Vec<Problem> {
+    let mut balance: Vec<HashMap<(i32, i32), i32>> = vec![HashMap::new(); map.sectors.len()];
+    for line in &map.linedefs {
+        let start = map.vertexes[line.start_vertex].map_units();
+        let end = map.vertexes[line.end_vertex].map_units();
+        if start == end {
+            continue;
+        }
+        for (side, from, to) in [(line.front_sidedef, start, end), (line.back_sidedef, end, start)] {
+            let Some(sidedef) = side else { continue };
+            let sector = &mut balance[map.sidedefs[sidedef].sector];
+            *sector.entry(from).or_default() -= 1;
+            *sector.entry(to).or_default() += 1;
+        }
+    }
+
+    balance.iter().enumerate()
+        .filter_map(|(sector, vertexes)| {
+            // the lowest loose end, so reports do not depend on hash order
+            let at = vertexes.iter().filter(|(_, &count)| count != 0).map(|(&at, _)| at).min()?;
+            Some(Problem::UnclosedSector { sector, at })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::MapError;
+    use crate::wad::{Identification, Wad, WadWriter};
+
+    const DOOM1: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/doom1.wad");
+
+    // the problems of E1M1 after `change`, checked against the shareware IWAD
+    fn lint_e1m1(change: impl FnOnce(&mut Map)) -> Vec<Problem> {
+        let wad = Wad::open(DOOM1).unwrap();
+        let lumps = MapLumps::find(&wad, "E1M1").unwrap();
+        let mut map = Map::from_lumps(&lumps).unwrap();
+        change(&mut map);
+        lint(&map, &lumps, &ResourceStack::new(Wad::open(DOOM1).unwrap()))
+    }
+
+    #[test]
+    fn finds_only_the_known_gap_in_shipped_maps() {
+        let wad = Wad::open(DOOM1).unwrap();
+        let resources = ResourceStack::new(Wad::open(DOOM1).unwrap());
+        for episode_map in 1..=9 {
+            let lumps = MapLumps::find(&wad, &format!("E1M{}", episode_map)).unwrap();
+            let map = Map::from_lumps(&lumps).unwrap();
+            let expected = match episode_map {
+                // id's map really has a gap between (-328, -1920) and (-320, -1920)
+                3 => vec![Problem::UnclosedSector { sector: 7, at: (-328, -1920) }],
+                _ => vec![],
+            };
+            assert_eq!(lint(&map, &lumps, &resources), expected, "E1M{}", episode_map);
+        }
+    }
+
+    #[test]
+    fn finds_unclosed_sectors() {
+        let problems = lint_e1m1(|map| {
+            let line = map.linedefs.iter().position(|line| line.back_sidedef.is_none()).unwrap();
+            map.linedefs.remove(line);
+        });
+        assert!(matches!(problems[..], [Problem::UnclosedSector { .. }]), "{:?}", problems);
+    }
+
+    #[test]
+    fn finds_broken_linedefs() {
+        let problems = lint_e1m1(|map| {
+            map.linedefs[0].front_sidedef = None;
+            map.linedefs[1].end_vertex = map.linedefs[1].start_vertex;
+        });
+        assert!(problems.iter().any(|problem| matches!(problem, Problem::NoFrontSide { linedef: 0, .. })));
+        assert!(problems.iter().any(|problem| matches!(problem, Problem::ZeroLengthLinedef { linedef: 1, .. })));
+    }
+
+    #[test]
+    fn finds_unknown_textures_and_flats() {
+        let problems = lint_e1m1(|map| {
+            map.sidedefs[0].middle_texture = LumpName::new("NOTHERE");
+            map.sectors[0].floor_texture = LumpName::new("NOFLAT");
+        });
+        assert!(matches!(problems[..], [
+            Problem::UnknownTexture { sidedef: 0, texture, at: Some(_) },
+            Problem::UnknownFlat { sector: 0, flat, at: Some(_) },
+        ] if texture == "NOTHERE" && flat == "NOFLAT"), "{:?}", problems);
+    }
+
+    #[test]
+    fn finds_bad_sectors_and_things() {
+        let problems = lint_e1m1(|map| {
+            map.sectors[0].floor_height = map.sectors[0].ceiling_height + 8;
+            map.things.retain(|thing| thing.thing_type != 1);
+        });
+        assert!(matches!(problems[..], [Problem::FloorAboveCeiling { sector: 0, .. }, Problem::NoPlayer1Start]),
+                "{:?}", problems);
+    }
+
+    #[test]
+    fn finds_exceeded_limits() {
+        let problems = lint_e1m1(|map| map.sectors.resize(VANILLA_INDEX_LIMIT + 1, map.sectors[0]));
+        assert!(problems.contains(&Problem::LimitExceeded { what: "sectors", count: 0x8000, limit: 0x7fff }));
+    }
+
+    #[test]
+    fn reports_unreadable_texture_lumps() {
+        let wad = Wad::open(DOOM1).unwrap();
+        let lumps = MapLumps::find(&wad, "E1M1").unwrap();
+        let mut iwad = WadWriter::new(Identification::IWAD);
+        iwad.add_lump("TEXTURE1", b"\x01\x00".to_vec());
+        let resources = ResourceStack::new(Wad::from_bytes(iwad.to_bytes()).unwrap());
+
+        let problems = lint(&Map::from_lumps(&lumps).unwrap(), &lumps, &resources);
+        assert!(problems.iter().any(|problem| matches!(problem, Problem::BadTextureLump(_))));
+    }
+
+    #[test]
+    fn bad_references_keep_maps_from_loading() {
+        let wad = Wad::open(DOOM1).unwrap();
+        let lumps = MapLumps::find(&wad, "E1M1").unwrap();
+        let mut sidedefs = lumps.get("SIDEDEFS").unwrap().to_vec();
+        sidedefs[28..30].copy_from_slice(&0xffffu16.to_le_bytes());
+        let broken = MapLumps {
+            name: lumps.name,
+            lumps: lumps.iter().map(|(name, data)| (name, if name == "SIDEDEFS" { &sidedefs[..] } else { data })).collect(),
+        };
+        assert!(matches!(Map::from_lumps(&broken), Err(MapError::SectorOutOfRange { sidedef: 0, sector: 0xffff })));
+    }
+}
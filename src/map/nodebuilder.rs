@@ -0,0 +1,461 @@
+use std::collections::{HashMap, HashSet};
+use crate::fixed::{Fixed, FRACUNIT};
+use crate::map::bsp::{bam_angle, BoundingBox, Bsp, Child, Node, Seg, Subsector};
+use crate::map::{Map, MapError, Vertex};
+
+// Distance in map units below which a point counts as lying on a partition line
+const EPSILON: f64 = 1.0 / 64.0;
+
+// Trees deeper than this only come from degenerate input; the rest becomes one subsector
+const MAX_DEPTH: usize = 512;
+
+/**
+ * Builds the BSP tree of a map that ships without NODES, SEGS and SSECTORS, or with broken
+ * ones.
+ *
+ * Every linedef side becomes a seg. The segs are split recursively by partition lines taken
+ * from the linedefs, choosing the line with the lowest cost: `split_cost` for every seg it cuts
+ * in two plus the difference in seg count between both sides. Ties go to the line seen first,
+ * so the same map always gives the same tree.
+ */
+pub struct NodeBuilder {
+    pub split_cost: usize,
+}
+
+impl Default for NodeBuilder {
+    fn default() -> Self {
+        NodeBuilder { split_cost: 8 }
+    }
+}
+
+// A seg under construction, running from vertex v1 to v2 of the builder's vertex list
+#[derive(Clone, Copy)]
+struct BuildSeg {
+    v1: usize,
+    v2: usize,
+    linedef: usize,
+    side: usize,
+}
+
+// A partition line through whole map units, taken from a linedef
+#[derive(Clone, Copy)]
+struct Partition {
+    x: f64,
+    y: f64,
+    dx: f64,
+    dy: f64,
+}
+
+enum Position {
+    Front,
+    Back,
+    // fraction along the seg where the partition line crosses it
+    Split(f64),
+}
+
+struct Builder<'a> {
+    map: &'a Map,
+    split_cost: usize,
+    points: Vec<(f64, f64)>,
+    point_index: HashMap<(Fixed, Fixed), usize>,
+    segs: Vec<Seg>,
+    subsectors: Vec<Subsector>,
+    nodes: Vec<Node>,
+}
+
+impl NodeBuilder {
+    pub fn build(&self, map: &Map) -> Result<Bsp, MapError> {
+        let points: Vec<(f64, f64)> = map.vertexes.iter()
+            .map(|v| (f64::from(v.x) / f64::from(FRACUNIT), f64::from(v.y) / f64::from(FRACUNIT)))
+            .collect();
+
+        let mut segs = Vec::new();
+        for (index, line) in map.linedefs.iter().enumerate() {
+            if map.vertexes[line.start_vertex] == map.vertexes[line.end_vertex] {
+                continue;
+            }
+            if line.front_sidedef.is_some() {
+                segs.push(BuildSeg { v1: line.start_vertex, v2: line.end_vertex, linedef: index, side: 0 });
+            }
+            if line.back_sidedef.is_some() {
+                segs.push(BuildSeg { v1: line.end_vertex, v2: line.start_vertex, linedef: index, side: 1 });
+            }
+        }
+        if segs.is_empty() {
+            return Err(MapError::BadBsp { lump: "LINEDEFS", index: 0, problem: "no linedef sides to build nodes from" });
+        }
+
+        let mut builder = Builder {
+            map,
+            split_cost: self.split_cost,
+            point_index: map.vertexes.iter().enumerate().rev().map(|(index, v)| ((v.x, v.y), index)).collect(),
+            points,
+            segs: Vec::new(),
+            subsectors: Vec::new(),
+            nodes: Vec::new(),
+        };
+        builder.build(segs, 0);
+
+        let vertexes = builder.points.iter()
+            .map(|&(x, y)| Vertex { x: to_fixed(x), y: to_fixed(y) })
+            .collect();
+        Bsp::new(map, vertexes, builder.segs, builder.subsectors, builder.nodes)
+    }
+}
+
+impl Builder<'_> {
+    /**
+     * Builds the subtree for a set of segs and returns it with its bounding box.
+     */
+    fn build(&mut self, segs: Vec<BuildSeg>, depth: usize) -> (Child, BoundingBox) {
+        let bbox = self.bounding_box(&segs);
+
+        let partition = if depth < MAX_DEPTH { self.choose_partition(&segs) } else { None };
+        let Some(partition) = partition else {
+            return (self.make_subsector(segs), bbox);
+        };
+
+        let (front, back) = self.split(segs, &partition);
+        let (front_child, front_bbox) = self.build(front, depth + 1);
+        let (back_child, back_bbox) = self.build(back, depth + 1);
+
+        self.nodes.push(Node {
+            x: to_fixed(partition.x),
+            y: to_fixed(partition.y),
+            dx: to_fixed(partition.dx),
+            dy: to_fixed(partition.dy),
+            bbox: [front_bbox, back_bbox],
+            children: [front_child, back_child],
+        });
+        (Child::Node(self.nodes.len() - 1), bbox)
+    }
+
+    /**
+     * The cheapest partition line with segs on both sides, `None` if the segs form a convex
+     * region.
+     */
+    fn choose_partition(&self, segs: &[BuildSeg]) -> Option<Partition> {
+        let mut tried = HashSet::new();
+        let mut best: Option<(usize, Partition)> = None;
+
+        'candidates: for seg in segs {
+            if !tried.insert(seg.linedef) {
+                continue;
+            }
+            let partition = self.partition_of(seg);
+            let (mut front, mut back, mut splits) = (0usize, 0usize, 0usize);
+
+            for other in segs {
+                match self.position(other, &partition) {
+                    Position::Front => front += 1,
+                    Position::Back => back += 1,
+                    Position::Split(_) => {
+                        splits += 1;
+                        front += 1;
+                        back += 1;
+                    }
+                }
+                if best.is_some_and(|(cost, _)| splits * self.split_cost > cost) {
+                    continue 'candidates;
+                }
+            }
+
+            // the candidate's own seg is always in front, so this only rejects lines with
+            // nothing behind them
+            if back == 0 {
+                continue;
+            }
+            let cost = splits * self.split_cost + front.abs_diff(back);
+            if best.is_none_or(|(best_cost, _)| cost < best_cost) {
+                best = Some((cost, partition));
+            }
+        }
+
+        best.map(|(_, partition)| partition)
+    }
+
+    fn partition_of(&self, seg: &BuildSeg) -> Partition {
+        let line = &self.map.linedefs[seg.linedef];
+        let (from, to) = if seg.side == 0 {
+            (line.start_vertex, line.end_vertex)
+        } else {
+            (line.end_vertex, line.start_vertex)
+        };
+        let (x, y) = self.points[from];
+        let (x2, y2) = self.points[to];
+        Partition { x, y, dx: x2 - x, dy: y2 - y }
+    }
+
+    /**
+     * Signed distance of a point to the partition line, positive on the front (right) side
+     * as `R_PointOnSide` sees it.
+     */
+    fn distance(&self, point: usize, partition: &Partition) -> f64 {
+        let (x, y) = self.points[point];
+        ((x - partition.x) * partition.dy - (y - partition.y) * partition.dx) / partition.dx.hypot(partition.dy)
+    }
+
+    fn position(&self, seg: &BuildSeg, partition: &Partition) -> Position {
+        let a = self.distance(seg.v1, partition);
+        let b = self.distance(seg.v2, partition);
+
+        if a.abs() < EPSILON && b.abs() < EPSILON {
+            // on the partition line: in front when running the same way
+            let (x1, y1) = self.points[seg.v1];
+            let (x2, y2) = self.points[seg.v2];
+            let dot = (x2 - x1) * partition.dx + (y2 - y1) * partition.dy;
+            return if dot > 0.0 { Position::Front } else { Position::Back };
+        }
+        if a > -EPSILON && b > -EPSILON {
+            Position::Front
+        } else if a < EPSILON && b < EPSILON {
+            Position::Back
+        } else {
+            Position::Split(a / (a - b))
+        }
+    }
+
+    fn split(&mut self, segs: Vec<BuildSeg>, partition: &Partition) -> (Vec<BuildSeg>, Vec<BuildSeg>) {
+        let mut front = Vec::new();
+        let mut back = Vec::new();
+
+        for seg in segs {
+            match self.position(&seg, partition) {
+                Position::Front => front.push(seg),
+                Position::Back => back.push(seg),
+                Position::Split(t) => {
+                    let (x1, y1) = self.points[seg.v1];
+                    let (x2, y2) = self.points[seg.v2];
+                    let middle = self.add_point(x1 + t * (x2 - x1), y1 + t * (y2 - y1));
+
+                    let first = BuildSeg { v2: middle, ..seg };
+                    let second = BuildSeg { v1: middle, ..seg };
+                    let start_in_front = self.distance(seg.v1, partition) > 0.0;
+
+                    // rounding the split point may land it on an end point
+                    if middle == seg.v1 || middle == seg.v2 {
+                        let far = if middle == seg.v1 { seg.v2 } else { seg.v1 };
+                        if self.distance(far, partition) > 0.0 { front.push(seg) } else { back.push(seg) }
+                    } else if start_in_front {
+                        front.push(first);
+                        back.push(second);
+                    } else {
+                        back.push(first);
+                        front.push(second);
+                    }
+                }
+            }
+        }
+
+        (front, back)
+    }
+
+    /**
+     * Adds a split point, rounded to fixed point precision so it stays exactly where the
+     * finished tree puts it.
+     */
+    fn add_point(&mut self, x: f64, y: f64) -> usize {
+        let key = (to_fixed(x), to_fixed(y));
+        if let Some(&index) = self.point_index.get(&key) {
+            return index;
+        }
+        let index = self.points.len();
+        let unit = f64::from(FRACUNIT);
+        self.points.push((f64::from(key.0) / unit, f64::from(key.1) / unit));
+        self.point_index.insert(key, index);
+        index
+    }
+
+    fn make_subsector(&mut self, segs: Vec<BuildSeg>) -> Child {
+        let first_seg = self.segs.len();
+        let seg_count = segs.len();
+
+        for seg in segs {
+            let (x1, y1) = self.points[seg.v1];
+            let (x2, y2) = self.points[seg.v2];
+            let line = &self.map.linedefs[seg.linedef];
+            let origin = if seg.side == 0 { line.start_vertex } else { line.end_vertex };
+            let (ox, oy) = self.points[origin];
+
+            self.segs.push(Seg {
+                start_vertex: seg.v1,
+                end_vertex: seg.v2,
+                angle: bam_angle(x2 - x1, y2 - y1),
+                linedef: Some(seg.linedef),
+                side: seg.side,
+                offset: to_fixed((x1 - ox).hypot(y1 - oy)),
+            });
+        }
+
+        self.subsectors.push(Subsector { first_seg, seg_count, sector: 0 });
+        Child::Subsector(self.subsectors.len() - 1)
+    }
+
+    fn bounding_box(&self, segs: &[BuildSeg]) -> BoundingBox {
+        let (mut left, mut bottom) = (f64::MAX, f64::MAX);
+        let (mut right, mut top) = (f64::MIN, f64::MIN);
+        for &point in segs.iter().flat_map(|seg| [&seg.v1, &seg.v2]) {
+            let (x, y) = self.points[point];
+            left = left.min(x);
+            right = right.max(x);
+            bottom = bottom.min(y);
+            top = top.max(y);
+        }
+        BoundingBox {
+            top: to_fixed(top.ceil()),
+            bottom: to_fixed(bottom.floor()),
+            left: to_fixed(left.floor()),
+            right: to_fixed(right.ceil()),
+        }
+    }
+}
+
+fn to_fixed(units: f64) -> Fixed {
+    (units * f64::from(FRACUNIT)).round() as Fixed
+}
+
+/**
+ * The vanilla VERTEXES, SEGS, SSECTORS and NODES lumps of a tree, ready to be written into a
+ * WAD. VERTEXES holds the map's vertexes followed by the split points, rounded to whole units.
+ */
+pub struct BspLumps {
+    pub vertexes: Vec<u8>,
+    pub segs: Vec<u8>,
+    pub ssectors: Vec<u8>,
+    pub nodes: Vec<u8>,
+}
+
+impl Bsp {
+    /**
+     * Encodes the tree in the vanilla lump formats. Fails if it exceeds their 16 bit limits
+     * or contains minisegs, which vanilla segs cannot express.
+     */
+    pub fn to_lumps(&self) -> Result<BspLumps, MapError> {
+        let too_many = |lump: &'static str, count: usize, limit: usize| if count > limit {
+            Err(MapError::BadBsp { lump, index: count, problem: "too many entries for the vanilla format" })
+        } else {
+            Ok(())
+        };
+        too_many("VERTEXES", self.vertexes.len(), 0xffff)?;
+        too_many("SEGS", self.segs.len(), 0x7fff)?;
+        too_many("SSECTORS", self.subsectors.len(), 0x7fff)?;
+        too_many("NODES", self.nodes.len(), 0x7fff)?;
+
+        let units = |value: Fixed| ((value + FRACUNIT / 2) >> 16) as i16;
+        let child = |child: Child| match child {
+            Child::Node(node) => node as u16,
+            Child::Subsector(subsector) => subsector as u16 | 0x8000,
+        };
+
+        let mut lumps = BspLumps { vertexes: Vec::new(), segs: Vec::new(), ssectors: Vec::new(), nodes: Vec::new() };
+
+        for vertex in &self.vertexes {
+            lumps.vertexes.extend(units(vertex.x).to_le_bytes());
+            lumps.vertexes.extend(units(vertex.y).to_le_bytes());
+        }
+
+        for (index, seg) in self.segs.iter().enumerate() {
+            let linedef = seg.linedef
+                .ok_or(MapError::BadBsp { lump: "SEGS", index, problem: "minisegs need an extended node format" })?;
+            lumps.segs.extend((seg.start_vertex as u16).to_le_bytes());
+            lumps.segs.extend((seg.end_vertex as u16).to_le_bytes());
+            lumps.segs.extend(((seg.angle >> 16) as u16).to_le_bytes());
+            lumps.segs.extend((linedef as u16).to_le_bytes());
+            lumps.segs.extend((seg.side as u16).to_le_bytes());
+            lumps.segs.extend(units(seg.offset).to_le_bytes());
+        }
+
+        for subsector in &self.subsectors {
+            lumps.ssectors.extend((subsector.seg_count as u16).to_le_bytes());
+            lumps.ssectors.extend((subsector.first_seg as u16).to_le_bytes());
+        }
+
+        for node in &self.nodes {
+            for value in [node.x, node.y, node.dx, node.dy] {
+                lumps.nodes.extend(units(value).to_le_bytes());
+            }
+            for bbox in &node.bbox {
+                for value in [bbox.top, bbox.bottom, bbox.left, bbox.right] {
+                    lumps.nodes.extend(units(value).to_le_bytes());
+                }
+            }
+            for &c in &node.children {
+                lumps.nodes.extend(child(c).to_le_bytes());
+            }
+        }
+
+        Ok(lumps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::MapLumps;
+    use crate::wad::{LumpName, Wad};
+
+    #[test]
+    fn builds_the_same_tree_every_time() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/doom1.wad");
+        let build = |name: &str| {
+            let map = Map::load(&Wad::open(path).unwrap(), name).unwrap();
+            NodeBuilder::default().build(&map).unwrap().to_lumps().unwrap()
+        };
+
+        for name in ["E1M1", "E1M5"] {
+            let (first, second) = (build(name), build(name));
+            assert!(first.nodes == second.nodes, "{} NODES differ", name);
+            assert!(first.segs == second.segs, "{} SEGS differ", name);
+            assert!(first.ssectors == second.ssectors, "{} SSECTORS differ", name);
+            assert!(first.vertexes == second.vertexes, "{} VERTEXES differ", name);
+        }
+    }
+
+    #[test]
+    fn agrees_with_the_shipped_nodes() {
+        let wad = Wad::open(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/doom1.wad")).unwrap();
+        for name in ["E1M1", "E1M2", "E1M3", "E1M4", "E1M5", "E1M6", "E1M7", "E1M8", "E1M9"] {
+            let lumps = MapLumps::find(&wad, name).unwrap();
+            let map = Map::from_lumps(&lumps).unwrap();
+            let shipped = Bsp::from_lumps(&lumps, &map).unwrap();
+            let built = NodeBuilder::default().build(&map).unwrap();
+
+            let things = map.things.iter().map(|thing| (thing.x, thing.y));
+            // nudged off the line, as the shipped nodes round split vertexes to whole units
+            let midpoints = map.linedefs.iter().map(|line| {
+                let (start, end) = (map.vertexes[line.start_vertex], map.vertexes[line.end_vertex]);
+                let (dx, dy) = ((end.x - start.x) / 2, (end.y - start.y) / 2);
+                let length = ((f64::from(dx).powi(2) + f64::from(dy).powi(2)).sqrt() / f64::from(FRACUNIT)).max(1.0);
+                // two units to the right, the front side
+                let (nx, ny) = ((f64::from(dy) / length * 2.0) as Fixed, (-f64::from(dx) / length * 2.0) as Fixed);
+                (start.x + dx + nx, start.y + dy + ny)
+            });
+            for (x, y) in things.chain(midpoints) {
+                assert_eq!(built.sector_at(x, y), shipped.sector_at(x, y), "{} at ({}, {})", name, x >> 16, y >> 16);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_through_the_lumps() {
+        let wad = Wad::open(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/doom1.wad")).unwrap();
+        let lumps = MapLumps::find(&wad, "E1M1").unwrap();
+        let built = NodeBuilder::default().build(&Map::from_lumps(&lumps).unwrap()).unwrap().to_lumps().unwrap();
+
+        let replaced = |name: LumpName, data| match name.to_string().as_str() {
+            "VERTEXES" => &built.vertexes[..],
+            "SEGS" => &built.segs[..],
+            "SSECTORS" => &built.ssectors[..],
+            "NODES" => &built.nodes[..],
+            _ => data,
+        };
+        let lumps = MapLumps { name: lumps.name, lumps: lumps.iter().map(|(name, data)| (name, replaced(name, data))).collect() };
+        let map = Map::from_lumps(&lumps).unwrap();
+        let loaded = Bsp::from_lumps(&lumps, &map).unwrap().to_lumps().unwrap();
+
+        assert!(loaded.vertexes == built.vertexes, "VERTEXES differ");
+        assert!(loaded.segs == built.segs, "SEGS differ");
+        assert!(loaded.ssectors == built.ssectors, "SSECTORS differ");
+        assert!(loaded.nodes == built.nodes, "NODES differ");
+    }
+}
@@ -0,0 +1,275 @@
+use std::fmt::Write;
+use crate::fixed::{Fixed, FRACUNIT};
+use crate::map::{Linedef, Map};
+use crate::png::encode_rgba;
+
+const BACKGROUND: [u8; 3] = [0x10, 0x10, 0x10];
+const ONE_SIDED: [u8; 3] = [0xe0, 0x30, 0x30];
+const TWO_SIDED: [u8; 3] = [0x80, 0x80, 0x80];
+const SECRET: [u8; 3] = [0xe0, 0x40, 0xe0];
+const SPECIAL: [u8; 3] = [0x30, 0xa0, 0xf0];
+const TAGGED: [u8; 3] = [0xf0, 0xc0, 0x30];
+
+// Space around the map, in pixels
+const MARGIN: f64 = 16.0;
+
+/**
+ * Draws a map's linedefs from above, for documentation and for checking that a map loads
+ * right without starting the game.
+ *
+ * One-sided lines are red, two-sided lines grey, secret lines purple and lines with a
+ * special blue. Things are drawn as squares coloured by what they are: players green,
+ * monsters red, keys yellow, pickups blue and everything else grey. Tags are written next to
+ * tagged lines and sectors in SVG; PNG images have no text and draw the lines of tagged
+ * sectors orange instead.
+ */
+pub struct Overview {
+    pub things: bool,
+    pub tags: bool,
+    // width or height of the image, whichever is larger, in pixels
+    pub size: u32,
+}
+
+impl Default for Overview {
+    fn default() -> Self {
+        Overview { things: false, tags: false, size: 1024 }
+    }
+}
+
+// Maps map units to pixels, y pointing down
+struct Projection {
+    min_x: f64,
+    max_y: f64,
+    scale: f64,
+    width: u32,
+    height: u32,
+}
+
+impl Projection {
+    fn new(map: &Map, size: u32) -> Projection {
+        let units: Vec<(f64, f64)> = map.vertexes.iter()
+            .map(|v| (units(v.x), units(v.y)))
+            .collect();
+        let min_x = units.iter().map(|p| p.0).fold(f64::MAX, f64::min);
+        let max_x = units.iter().map(|p| p.0).fold(f64::MIN, f64::max);
+        let min_y = units.iter().map(|p| p.1).fold(f64::MAX, f64::min);
+        let max_y = units.iter().map(|p| p.1).fold(f64::MIN, f64::max);
+        if units.is_empty() {
+            return Projection { min_x: 0.0, max_y: 0.0, scale: 1.0, width: size.max(1), height: size.max(1) };
+        }
+
+        let extent = (max_x - min_x).max(max_y - min_y).max(1.0);
+        let scale = (f64::from(size) - 2.0 * MARGIN).max(1.0) / extent;
+        Projection {
+            min_x,
+            max_y,
+            scale,
+            width: ((max_x - min_x) * scale + 2.0 * MARGIN).ceil() as u32,
+            height: ((max_y - min_y) * scale + 2.0 * MARGIN).ceil() as u32,
+        }
+    }
+
+    fn point(&self, x: Fixed, y: Fixed) -> (f64, f64) {
+        let (x, y) = (units(x), units(y));
+        ((x - self.min_x) * self.scale + MARGIN, (self.max_y - y) * self.scale + MARGIN)
+    }
+}
+
+impl Overview {
+    pub fn svg(&self, map: &Map) -> String {
+        let projection = Projection::new(map, self.size);
+        let color = |[r, g, b]: [u8; 3]| format!("#{:02x}{:02x}{:02x}", r, g, b);
+        let mut svg = String::new();
+
+        let _ = writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {0} {1}">"#,
+                         projection.width, projection.height);
+        let _ = writeln!(svg, r#"<rect width="100%" height="100%" fill="{}"/>"#, color(BACKGROUND));
+        let _ = writeln!(svg, r#"<title>{}</title>"#, escape(&map.name.to_string()));
+
+        for index in draw_order(map) {
+            let line = &map.linedefs[index];
+            let (x1, y1) = projection.point(map.vertexes[line.start_vertex].x, map.vertexes[line.start_vertex].y);
+            let (x2, y2) = projection.point(map.vertexes[line.end_vertex].x, map.vertexes[line.end_vertex].y);
+            let _ = writeln!(svg, r#"<line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="{}"/>"#,
+                             x1, y1, x2, y2, color(line_color(map, line, false)));
+
+            if self.tags {
+                let (mx, my) = ((x1 + x2) / 2.0, (y1 + y2) / 2.0);
+                let sector_tag = sector_tag(map, line);
+                if line.tag != 0 {
+                    let _ = writeln!(svg, r#"<text x="{:.1}" y="{:.1}" font-size="9" fill="{}">{}</text>"#,
+                                     mx, my, color(SPECIAL), line.tag);
+                } else if let Some(tag) = sector_tag {
+                    let _ = writeln!(svg, r#"<text x="{:.1}" y="{:.1}" font-size="9" fill="{}">{}</text>"#,
+                                     mx, my, color(TAGGED), tag);
+                }
+            }
+        }
+
+        if self.things {
+            for thing in &map.things {
+                let (x, y) = projection.point(thing.x, thing.y);
+                let _ = writeln!(svg, r#"<rect x="{:.1}" y="{:.1}" width="4" height="4" fill="{}"><title>{}</title></rect>"#,
+                                 x - 2.0, y - 2.0, color(thing_color(thing.thing_type)), escape(&thing.thing_type.to_string()));
+            }
+        }
+
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    pub fn png(&self, map: &Map) -> Vec<u8> {
+        let projection = Projection::new(map, self.size);
+        let (width, height) = (projection.width as usize, projection.height as usize);
+        let mut pixels = BACKGROUND.repeat(width * height);
+
+        let mut plot = |x: i64, y: i64, rgb: [u8; 3]| {
+            if (0..width as i64).contains(&x) && (0..height as i64).contains(&y) {
+                let offset = (y as usize * width + x as usize) * 3;
+                pixels[offset..offset + 3].copy_from_slice(&rgb);
+            }
+        };
+
+        for index in draw_order(map) {
+            let line = &map.linedefs[index];
+            let (x1, y1) = projection.point(map.vertexes[line.start_vertex].x, map.vertexes[line.start_vertex].y);
+            let (x2, y2) = projection.point(map.vertexes[line.end_vertex].x, map.vertexes[line.end_vertex].y);
+            let rgb = line_color(map, line, self.tags);
+
+            // Bresenham
+            let (mut x, mut y) = (x1.round() as i64, y1.round() as i64);
+            let (end_x, end_y) = (x2.round() as i64, y2.round() as i64);
+            let (dx, dy) = ((end_x - x).abs(), -(end_y - y).abs());
+            let (step_x, step_y) = ((end_x - x).signum(), (end_y - y).signum());
+            let mut error = dx + dy;
+            loop {
+                plot(x, y, rgb);
+                if x == end_x && y == end_y {
+                    break;
+                }
+                let doubled = 2 * error;
+                if doubled >= dy {
+                    error += dy;
+                    x += step_x;
+                }
+                if doubled <= dx {
+                    error += dx;
+                    y += step_y;
+                }
+            }
+        }
+
+        if self.things {
+            for thing in &map.things {
+                let (x, y) = projection.point(thing.x, thing.y);
+                let (x, y) = (x.round() as i64, y.round() as i64);
+                for (px, py) in (-2..2).flat_map(|dx| (-2..2).map(move |dy| (x + dx, y + dy))) {
+                    plot(px, py, thing_color(thing.thing_type));
+                }
+            }
+        }
+
+        let rgba: Vec<u8> = pixels.chunks_exact(3).flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 0xff]).collect();
+        encode_rgba(projection.width, projection.height, &rgba)
+    }
+}
+
+// Lump names may hold any bytes, including those XML treats as markup
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn units(value: Fixed) -> f64 {
+    f64::from(value) / f64::from(FRACUNIT)
+}
+
+// Unremarkable lines first, so the interesting ones are drawn over them
+fn draw_order(map: &Map) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..map.linedefs.len()).collect();
+    order.sort_by_key(|&index| {
+        let line = &map.linedefs[index];
+        if line.flags & Linedef::SECRET != 0 {
+            3
+        } else if line.special != 0 {
+            2
+        } else if line.back_sidedef.is_none() {
+            1
+        } else {
+            0
+        }
+    });
+    order
+}
+
+fn line_color(map: &Map, line: &Linedef, tags: bool) -> [u8; 3] {
+    if line.flags & Linedef::SECRET != 0 {
+        SECRET
+    } else if line.special != 0 {
+        SPECIAL
+    } else if tags && sector_tag(map, line).is_some() {
+        TAGGED
+    } else if line.back_sidedef.is_none() {
+        ONE_SIDED
+    } else {
+        TWO_SIDED
+    }
+}
+
+// The tag of a tagged sector on either side of a line
+fn sector_tag(map: &Map, line: &Linedef) -> Option<u16> {
+    [line.front_sidedef, line.back_sidedef].into_iter().flatten()
+        .map(|side| map.sectors[map.sidedefs[side].sector].tag)
+        .find(|&tag| tag != 0)
+}
+
+fn thing_color(thing_type: u16) -> [u8; 3] {
+    match thing_type {
+        // player starts and deathmatch starts
+        1..=4 | 11 => [0x40, 0xe0, 0x40],
+        // monsters
+        7 | 9 | 16 | 58 | 64..=69 | 71 | 84 | 3001..=3006 => [0xf0, 0x40, 0x40],
+        // keys and skulls
+        5 | 6 | 13 | 38..=40 => [0xf0, 0xf0, 0x40],
+        // weapons, ammunition, health, armour and power-ups
+        8 | 17 | 82 | 83 | 2001..=2026 | 2045..=2049 => [0x40, 0x90, 0xf0],
+        _ => [0xa0, 0xa0, 0xa0],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wad::{LumpName, Wad};
+
+    fn e1m1() -> Map {
+        Map::load(&Wad::open(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/doom1.wad")).unwrap(), "E1M1").unwrap()
+    }
+
+    #[test]
+    fn draws_every_line_and_thing_in_svg() {
+        let mut map = e1m1();
+        map.name = LumpName::from_bytes(b"A&B<C>");
+        let svg = Overview { things: true, ..Overview::default() }.svg(&map);
+
+        assert!(svg.contains("<title>A&amp;B&lt;C&gt;</title>\n"));
+        assert_eq!(svg.matches("<line ").count(), map.linedefs.len());
+        assert_eq!(svg.matches("<rect ").count(), map.things.len() + 1);
+        assert!(svg.ends_with("</svg>\n"));
+    }
+
+    #[test]
+    fn fits_png_into_the_size() {
+        let map = e1m1();
+        let png = Overview { size: 256, ..Overview::default() }.png(&map);
+        let width = u32::from_be_bytes(png[16..20].try_into().unwrap());
+        let height = u32::from_be_bytes(png[20..24].try_into().unwrap());
+        assert_eq!(width.max(height), 256);
+
+        let mut empty = map;
+        empty.vertexes.clear();
+        empty.linedefs.clear();
+        empty.things.clear();
+        let png = Overview { size: 0, ..Overview::default() }.png(&empty);
+        assert_eq!(&png[16..24], [0, 0, 0, 1, 0, 0, 0, 1]);
+    }
+}
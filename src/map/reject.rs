@@ -0,0 +1,58 @@
+/**
+ * The REJECT lump: a bit matrix telling for every pair of sectors whether a monster in the
+ * first can possibly see into the second. A set bit skips the line of sight check.
+ *
+ * Bits beyond the end of a short or missing lump read as clear, so those sectors are
+ * considered visible and the full check decides.
+ */
+pub struct Reject {
+    sectors: usize,
+    bits: Vec<u8>,
+}
+
+impl Reject {
+    pub fn from_lump(lump: Option<&[u8]>, sectors: usize) -> Reject {
+        Reject { sectors, bits: lump.unwrap_or_default().to_vec() }
+    }
+
+    /**
+     * A table rejecting nothing, for maps without a REJECT lump.
+     */
+    pub fn all_visible(sectors: usize) -> Reject {
+        Reject { sectors, bits: Vec::new() }
+    }
+
+    /**
+     * Whether the table rules out sight from sector `from` into sector `to`.
+     */
+    pub fn is_rejected(&self, from: usize, to: usize) -> bool {
+        let bit = from * self.sectors + to;
+        self.bits.get(bit >> 3).is_some_and(|byte| byte & (1 << (bit & 7)) != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_bits_row_by_row() {
+        // 3 sectors: 0 cannot see 1, 1 cannot see 2 and 2 cannot see 0
+        let reject = Reject::from_lump(Some(&[0b0110_0010, 0]), 3);
+        let rejected: Vec<(usize, usize)> = (0..3)
+            .flat_map(|from| (0..3).map(move |to| (from, to)))
+            .filter(|&(from, to)| reject.is_rejected(from, to))
+            .collect();
+        assert_eq!(rejected, [(0, 1), (1, 2), (2, 0)]);
+    }
+
+    #[test]
+    fn short_lumps_reject_nothing_beyond_their_end() {
+        let reject = Reject::from_lump(Some(&[0xff]), 4);
+        assert!(reject.is_rejected(1, 3));
+        assert!(!reject.is_rejected(2, 0));
+        assert!(!reject.is_rejected(3, 3));
+        assert!(!Reject::from_lump(None, 4).is_rejected(0, 0));
+        assert!(!Reject::all_visible(4).is_rejected(0, 0));
+    }
+}
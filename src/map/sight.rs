@@ -0,0 +1,257 @@
+use crate::fixed::{fixed_div, fixed_mul, to_fixed, Fixed, FRACBITS};
+use crate::map::blockmap::ValidCount;
+use crate::map::bsp::{Bsp, Child};
+use crate::map::reject::Reject;
+use crate::map::{Linedef, Map};
+
+/**
+ * One end of a line of sight: a thing's position, the height of its feet and its height.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct SightPoint {
+    pub x: Fixed,
+    pub y: Fixed,
+    pub z: Fixed,
+    pub height: Fixed,
+}
+
+// A line through a point, vanilla's `divline_t`
+#[derive(Clone, Copy)]
+struct Divline {
+    x: Fixed,
+    y: Fixed,
+    dx: Fixed,
+    dy: Fixed,
+}
+
+// The line of sight being traced and the vertical window still open along it
+struct Trace {
+    line: Divline,
+    to_x: Fixed,
+    to_y: Fixed,
+    z_start: Fixed,
+    top_slope: Fixed,
+    bottom_slope: Fixed,
+}
+
+/**
+ * Line of sight checks between things, done like vanilla's `P_CheckSight`: the REJECT table
+ * is consulted first, then the BSP tree is walked from the looker's side along the line of
+ * sight, narrowing the visible window at every two-sided line crossed.
+ */
+pub struct LineOfSight<'a> {
+    map: &'a Map,
+    bsp: &'a Bsp,
+    reject: &'a Reject,
+    valid: ValidCount,
+}
+
+impl<'a> LineOfSight<'a> {
+    pub fn new(map: &'a Map, bsp: &'a Bsp, reject: &'a Reject) -> LineOfSight<'a> {
+        LineOfSight { map, bsp, reject, valid: ValidCount::new(map.linedefs.len()) }
+    }
+
+    /**
+     * Whether a thing at `from` can see one at `to`, looking from three quarters of its
+     * height to anywhere between the other's feet and head.
+     */
+    pub fn check_sight(&mut self, from: &SightPoint, to: &SightPoint) -> bool {
+        let from_sector = self.bsp.sector_at(from.x, from.y);
+        let to_sector = self.bsp.sector_at(to.x, to.y);
+        if self.reject.is_rejected(from_sector, to_sector) {
+            return false;
+        }
+
+        self.valid.next();
+        let z_start = from.z + from.height - (from.height >> 2);
+        let mut trace = Trace {
+            line: Divline { x: from.x, y: from.y, dx: to.x.wrapping_sub(from.x), dy: to.y.wrapping_sub(from.y) },
+            to_x: to.x,
+            to_y: to.y,
+            z_start,
+            top_slope: to.z + to.height - z_start,
+            bottom_slope: to.z - z_start,
+        };
+        self.cross_bsp_node(self.bsp.root(), &mut trace)
+    }
+
+    /**
+     * Whether the line of sight passes through a subtree, vanilla's `P_CrossBSPNode`.
+     */
+    fn cross_bsp_node(&mut self, child: Child, trace: &mut Trace) -> bool {
+        let node = match child {
+            Child::Subsector(subsector) => return self.cross_subsector(subsector, trace),
+            Child::Node(node) => &self.bsp.nodes[node],
+        };
+        let partition = Divline { x: node.x, y: node.y, dx: node.dx, dy: node.dy };
+
+        // a start exactly on the partition line counts as in front
+        let side = match divline_side(trace.line.x, trace.line.y, &partition) {
+            2 => 0,
+            side => side,
+        };
+        let children = node.children;
+
+        if !self.cross_bsp_node(children[side], trace) {
+            return false;
+        }
+        if side == divline_side(trace.to_x, trace.to_y, &partition) {
+            // the line of sight ends on this side
+            return true;
+        }
+        self.cross_bsp_node(children[side ^ 1], trace)
+    }
+
+    /**
+     * Whether the line of sight passes through the lines of a subsector, vanilla's
+     * `P_CrossSubsector`.
+     */
+    fn cross_subsector(&mut self, subsector: usize, trace: &mut Trace) -> bool {
+        for seg in self.bsp.subsector_segs(subsector) {
+            let Some(index) = seg.linedef else { continue };
+            if !self.valid.check(index) {
+                continue;
+            }
+
+            let line = &self.map.linedefs[index];
+            let v1 = self.map.vertexes[line.start_vertex];
+            let v2 = self.map.vertexes[line.end_vertex];
+
+            // the line must cross the line of sight, and the line of sight the line
+            if divline_side(v1.x, v1.y, &trace.line) == divline_side(v2.x, v2.y, &trace.line) {
+                continue;
+            }
+            let divline = Divline { x: v1.x, y: v1.y, dx: v2.x.wrapping_sub(v1.x), dy: v2.y.wrapping_sub(v1.y) };
+            if divline_side(trace.line.x, trace.line.y, &divline) == divline_side(trace.to_x, trace.to_y, &divline) {
+                continue;
+            }
+
+            // one-sided lines block sight
+            if line.flags & Linedef::TWO_SIDED == 0 {
+                return false;
+            }
+            let (Some(front), Some(back)) = (self.map.side_sector(index, seg.side), self.map.side_sector(index, seg.side ^ 1))
+            else {
+                return false;
+            };
+            let front = &self.map.sectors[front];
+            let back = &self.map.sectors[back];
+
+            if front.floor_height == back.floor_height && front.ceiling_height == back.ceiling_height {
+                continue;
+            }
+
+            let open_top = to_fixed(front.ceiling_height.min(back.ceiling_height).into());
+            let open_bottom = to_fixed(front.floor_height.max(back.floor_height).into());
+            if open_bottom >= open_top {
+                // closed door
+                return false;
+            }
+
+            let frac = intercept_vector(&trace.line, &divline);
+            if front.floor_height != back.floor_height {
+                trace.bottom_slope = trace.bottom_slope.max(fixed_div(open_bottom - trace.z_start, frac));
+            }
+            if front.ceiling_height != back.ceiling_height {
+                trace.top_slope = trace.top_slope.min(fixed_div(open_top - trace.z_start, frac));
+            }
+            if trace.top_slope <= trace.bottom_slope {
+                return false;
+            }
+        }
+
+        // passed the subsector ok
+        true
+    }
+}
+
+/**
+ * The side of a line a point is on: 0 in front, 1 behind, 2 on the line. Vanilla's
+ * `P_DivlineSide`, including its check of x against the line's y for horizontal lines.
+ */
+fn divline_side(x: Fixed, y: Fixed, line: &Divline) -> usize {
+    if line.dx == 0 {
+        if x == line.x {
+            return 2;
+        }
+        if x <= line.x {
+            return usize::from(line.dy > 0);
+        }
+        return usize::from(line.dy < 0);
+    }
+    if line.dy == 0 {
+        if x == line.y {
+            return 2;
+        }
+        if y <= line.y {
+            return usize::from(line.dx < 0);
+        }
+        return usize::from(line.dx > 0);
+    }
+
+    // vanilla overflows silently here
+    let dx = x.wrapping_sub(line.x);
+    let dy = y.wrapping_sub(line.y);
+    let left = (line.dy >> FRACBITS).wrapping_mul(dx >> FRACBITS);
+    let right = (dy >> FRACBITS).wrapping_mul(line.dx >> FRACBITS);
+
+    if right < left {
+        0
+    } else if left == right {
+        2
+    } else {
+        1
+    }
+}
+
+/**
+ * How far along `trace` it meets `line`, as a fraction in fixed point. Vanilla's
+ * `P_InterceptVector2`, which gives 0 for parallel lines.
+ */
+fn intercept_vector(trace: &Divline, line: &Divline) -> Fixed {
+    let den = fixed_mul(line.dy >> 8, trace.dx).wrapping_sub(fixed_mul(line.dx >> 8, trace.dy));
+    if den == 0 {
+        return 0;
+    }
+    let num = fixed_mul(line.x.wrapping_sub(trace.x) >> 8, line.dy)
+        .wrapping_add(fixed_mul(trace.y.wrapping_sub(line.y) >> 8, line.dx));
+    fixed_div(num, den)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::MapLumps;
+    use crate::wad::Wad;
+
+    #[test]
+    fn checks_sight_between_things() {
+        let wad = Wad::open(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/doom1.wad")).unwrap();
+        let lumps = MapLumps::find(&wad, "E1M1").unwrap();
+        let map = Map::from_lumps(&lumps).unwrap();
+        let bsp = Bsp::from_lumps(&lumps, &map).unwrap();
+        let point = |thing: usize| {
+            let thing = &map.things[thing];
+            let sector = &map.sectors[bsp.sector_at(thing.x, thing.y)];
+            SightPoint { x: thing.x, y: thing.y, z: to_fixed(sector.floor_height.into()), height: to_fixed(56) }
+        };
+        // things 0 and 1 are the player 1 and 2 starts in the first room, thing 8 an imp
+        // behind several walls further east
+        let (player1, player2, imp) = (point(0), point(1), point(8));
+
+        let reject = Reject::from_lump(lumps.get("REJECT"), map.sectors.len());
+        let mut sight = LineOfSight::new(&map, &bsp, &reject);
+        assert!(sight.check_sight(&player1, &player2));
+        assert!(sight.check_sight(&player2, &player1));
+        assert!(!sight.check_sight(&player1, &imp));
+        assert!(!sight.check_sight(&imp, &player1));
+
+        // a REJECT lump ruling out the first room seeing itself
+        let sector = bsp.sector_at(player1.x, player1.y);
+        let bit = sector * map.sectors.len() + sector;
+        let mut lump = vec![0; (map.sectors.len() * map.sectors.len()).div_ceil(8)];
+        lump[bit >> 3] |= 1 << (bit & 7);
+        let reject = Reject::from_lump(Some(&lump), map.sectors.len());
+        assert!(!LineOfSight::new(&map, &bsp, &reject).check_sight(&player1, &player2));
+    }
+}
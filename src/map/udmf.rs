@@ -0,0 +1,543 @@
+use std::collections::HashMap;
+use crate::fixed::{Fixed, FRACUNIT};
+use crate::map::{ExtendedThing, Linedef, Map, MapError, MapFormat, Sector, Sidedef, Thing, Vertex};
+use crate::wad::LumpName;
+
+/**
+ * The UDMF namespaces Room understands. Doom, Boom and ZDoomTranslated maps use vanilla
+ * specials and thing flags; ZDoom maps use Hexen style specials with arguments and Hexen
+ * linedef and thing flags.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UdmfNamespace {
+    Doom,
+    Boom,
+    ZDoom,
+    ZDoomTranslated,
+}
+
+/**
+ * The value of a TEXTMAP field.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    String(String),
+}
+
+/**
+ * The kinds of map element a TEXTMAP block can describe.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Element {
+    Thing,
+    Vertex,
+    Linedef,
+    Sidedef,
+    Sector,
+}
+
+/**
+ * TEXTMAP fields that have no place in Room's map types, such as port specific flags and
+ * user_ fields, kept by element and index. Empty for binary maps.
+ */
+#[derive(Default)]
+pub struct Properties {
+    global: Vec<(String, Value)>,
+    elements: HashMap<(Element, usize), Vec<(String, Value)>>,
+}
+
+impl Properties {
+    pub fn is_empty(&self) -> bool {
+        self.global.is_empty() && self.elements.is_empty()
+    }
+
+    /**
+     * The fields outside of any block, other than the namespace.
+     */
+    pub fn global(&self) -> &[(String, Value)] {
+        &self.global
+    }
+
+    /**
+     * The remaining fields of an element, keys in lower case.
+     */
+    pub fn of(&self, element: Element, index: usize) -> &[(String, Value)] {
+        self.elements.get(&(element, index)).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn get(&self, element: Element, index: usize, key: &str) -> Option<&Value> {
+        self.of(element, index).iter().rev().find(|(name, _)| name == key).map(|(_, value)| value)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Identifier(String),
+    Value(Value),
+    Symbol(u8),
+}
+
+struct Tokenizer<'a> {
+    text: &'a [u8],
+    offset: usize,
+    line: usize,
+}
+
+impl Tokenizer<'_> {
+    fn error(&self, problem: impl Into<String>) -> MapError {
+        MapError::BadTextmap { line: self.line, problem: problem.into() }
+    }
+
+    fn skip_blanks(&mut self) {
+        loop {
+            match self.text.get(self.offset..).unwrap_or_default() {
+                [b'\n', ..] => {
+                    self.line += 1;
+                    self.offset += 1;
+                }
+                [c, ..] if c.is_ascii_whitespace() => self.offset += 1,
+                [b'/', b'/', ..] => {
+                    while self.text.get(self.offset).is_some_and(|&c| c != b'\n') {
+                        self.offset += 1;
+                    }
+                }
+                [b'/', b'*', ..] => {
+                    self.offset += 2;
+                    while self.offset < self.text.len() && !self.text[self.offset..].starts_with(b"*/") {
+                        if self.text[self.offset] == b'\n' {
+                            self.line += 1;
+                        }
+                        self.offset += 1;
+                    }
+                    self.offset += 2;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<Token>, MapError> {
+        self.skip_blanks();
+        let Some(&first) = self.text.get(self.offset) else { return Ok(None) };
+        let start = self.offset;
+
+        if first == b'"' {
+            let mut string = Vec::new();
+            self.offset += 1;
+            loop {
+                match self.text.get(self.offset) {
+                    None => return Err(self.error("unterminated string")),
+                    Some(b'"') => break,
+                    Some(b'\\') if self.offset + 1 < self.text.len() => {
+                        string.push(self.text[self.offset + 1]);
+                        self.offset += 2;
+                    }
+                    Some(&c) => {
+                        if c == b'\n' {
+                            self.line += 1;
+                        }
+                        string.push(c);
+                        self.offset += 1;
+                    }
+                }
+            }
+            self.offset += 1;
+            return Ok(Some(Token::Value(Value::String(String::from_utf8_lossy(&string).into_owned()))));
+        }
+
+        if first.is_ascii_alphabetic() || first == b'_' {
+            while self.text.get(self.offset).is_some_and(|c| c.is_ascii_alphanumeric() || *c == b'_') {
+                self.offset += 1;
+            }
+            let word = String::from_utf8_lossy(&self.text[start..self.offset]).to_ascii_lowercase();
+            return Ok(Some(match word.as_str() {
+                "true" => Token::Value(Value::Boolean(true)),
+                "false" => Token::Value(Value::Boolean(false)),
+                _ => Token::Identifier(word),
+            }));
+        }
+
+        if first.is_ascii_digit() || matches!(first, b'+' | b'-' | b'.') {
+            self.offset += 1;
+            while self.text.get(self.offset).is_some_and(|c| c.is_ascii_alphanumeric() || *c == b'.'
+                || (matches!(c, b'+' | b'-') && matches!(self.text[self.offset - 1], b'e' | b'E'))) {
+                self.offset += 1;
+            }
+            let number = std::str::from_utf8(&self.text[start..self.offset]).unwrap_or_default();
+            return parse_number(number)
+                .map(|value| Some(Token::Value(value)))
+                .ok_or_else(|| self.error(format!("bad number {}", number)));
+        }
+
+        self.offset += 1;
+        Ok(Some(Token::Symbol(first)))
+    }
+
+    fn expect(&mut self, symbol: u8) -> Result<(), MapError> {
+        match self.next()? {
+            Some(Token::Symbol(c)) if c == symbol => Ok(()),
+            _ => Err(self.error(format!("expected '{}'", symbol as char))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, MapError> {
+        match self.next()? {
+            Some(Token::Value(value)) => Ok(value),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+}
+
+// Decimal, hexadecimal (0x) and octal (leading 0) integers, and decimal floats
+fn parse_number(number: &str) -> Option<Value> {
+    let (negative, digits) = match number.as_bytes().first()? {
+        b'-' => (true, &number[1..]),
+        b'+' => (false, &number[1..]),
+        _ => (false, number),
+    };
+    let sign = if negative { -1 } else { 1 };
+
+    if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        return i64::from_str_radix(hex, 16).ok().map(|value| Value::Integer(sign * value));
+    }
+    if digits.contains(['.', 'e', 'E']) {
+        return digits.parse::<f64>().ok().map(|value| Value::Float(sign as f64 * value));
+    }
+    if digits.len() > 1 && digits.starts_with('0') {
+        return i64::from_str_radix(&digits[1..], 8).ok().map(|value| Value::Integer(sign * value));
+    }
+    digits.parse::<i64>().ok().map(|value| Value::Integer(sign * value))
+}
+
+// A block of the TEXTMAP, whose fields are taken out as they are converted
+struct Block {
+    kind: String,
+    line: usize,
+    fields: Vec<(String, Value)>,
+}
+
+impl Block {
+    fn error(&self, problem: String) -> MapError {
+        MapError::BadTextmap { line: self.line, problem }
+    }
+
+    // the last assignment of a field wins
+    fn take(&mut self, key: &str) -> Option<Value> {
+        let mut value = None;
+        self.fields.retain(|(name, v)| if name == key {
+            value = Some(v.clone());
+            false
+        } else {
+            true
+        });
+        value
+    }
+
+    fn missing(&self, key: &str) -> MapError {
+        self.error(format!("{} has no {}", self.kind, key))
+    }
+
+    fn wrong_type(&self, key: &str) -> MapError {
+        self.error(format!("{} of {} has the wrong type", key, self.kind))
+    }
+
+    fn int<T: TryFrom<i64>>(&mut self, key: &str, default: Option<T>) -> Result<T, MapError> {
+        match self.take(key) {
+            None => default.ok_or_else(|| self.missing(key)),
+            Some(Value::Integer(value)) => T::try_from(value)
+                .map_err(|_| self.error(format!("{} of {} is out of range", key, self.kind))),
+            Some(_) => Err(self.wrong_type(key)),
+        }
+    }
+
+    fn fixed(&mut self, key: &str, default: Option<Fixed>) -> Result<Fixed, MapError> {
+        let units = match self.take(key) {
+            None => return default.ok_or_else(|| self.missing(key)),
+            Some(Value::Float(value)) => value,
+            Some(Value::Integer(value)) => value as f64,
+            Some(_) => return Err(self.wrong_type(key)),
+        };
+        Ok((units * f64::from(FRACUNIT)).round() as Fixed)
+    }
+
+    fn flag(&mut self, key: &str) -> Result<bool, MapError> {
+        match self.take(key) {
+            None => Ok(false),
+            Some(Value::Boolean(value)) => Ok(value),
+            Some(_) => Err(self.wrong_type(key)),
+        }
+    }
+
+    fn texture(&mut self, key: &str, default: Option<&str>) -> Result<LumpName, MapError> {
+        match self.take(key) {
+            None => default.map(LumpName::new).ok_or_else(|| self.missing(key)),
+            Some(Value::String(value)) => Ok(LumpName::new(&value)),
+            Some(_) => Err(self.wrong_type(key)),
+        }
+    }
+
+    // sets the bits of the flags that are true
+    fn flags(&mut self, bits: &[(&str, u16)]) -> Result<u16, MapError> {
+        let mut flags = 0;
+        for &(key, bit) in bits {
+            if self.flag(key)? {
+                flags |= bit;
+            }
+        }
+        Ok(flags)
+    }
+
+    fn args(&mut self) -> Result<[i32; 5], MapError> {
+        let mut args = [0; 5];
+        for (index, arg) in args.iter_mut().enumerate() {
+            *arg = self.int(&format!("arg{}", index), Some(0))?;
+        }
+        Ok(args)
+    }
+}
+
+/**
+ * Reads a TEXTMAP lump into a map.
+ */
+pub(crate) fn parse(name: LumpName, textmap: &[u8]) -> Result<Map, MapError> {
+    let mut tokenizer = Tokenizer { text: textmap, offset: 0, line: 1 };
+    let mut global = Vec::new();
+    let mut blocks = Vec::new();
+
+    while let Some(token) = tokenizer.next()? {
+        let Token::Identifier(key) = token else { return Err(tokenizer.error("expected a field or block name")) };
+        let line = tokenizer.line;
+        match tokenizer.next()? {
+            Some(Token::Symbol(b'=')) => {
+                global.push((key, tokenizer.value()?));
+                tokenizer.expect(b';')?;
+            }
+            Some(Token::Symbol(b'{')) => {
+                let mut fields = Vec::new();
+                loop {
+                    match tokenizer.next()? {
+                        Some(Token::Symbol(b'}')) => break,
+                        Some(Token::Identifier(field)) => {
+                            tokenizer.expect(b'=')?;
+                            fields.push((field, tokenizer.value()?));
+                            tokenizer.expect(b';')?;
+                        }
+                        _ => return Err(tokenizer.error(format!("unterminated {} block", key))),
+                    }
+                }
+                blocks.push(Block { kind: key, line, fields });
+            }
+            _ => return Err(tokenizer.error(format!("expected '=' or '{{' after {}", key))),
+        }
+    }
+
+    let namespace = match global.iter().position(|(key, _)| key == "namespace").map(|index| global.remove(index)) {
+        Some((_, Value::String(namespace))) => match namespace.to_ascii_lowercase().as_str() {
+            "doom" => UdmfNamespace::Doom,
+            "boom" => UdmfNamespace::Boom,
+            "zdoom" => UdmfNamespace::ZDoom,
+            "zdoomtranslated" => UdmfNamespace::ZDoomTranslated,
+            _ => return Err(MapError::BadTextmap { line: 1, problem: format!("unsupported namespace {}", namespace) }),
+        },
+        _ => return Err(MapError::BadTextmap { line: 1, problem: "no namespace".to_string() }),
+    };
+
+    let mut map = Map {
+        name,
+        format: MapFormat::Udmf(namespace),
+        vertexes: Vec::new(),
+        linedefs: Vec::new(),
+        sidedefs: Vec::new(),
+        sectors: Vec::new(),
+        things: Vec::new(),
+        behavior: None,
+        properties: Properties { global, elements: HashMap::new() },
+    };
+    let zdoom = namespace == UdmfNamespace::ZDoom;
+
+    for mut block in blocks {
+        let (element, index) = match block.kind.as_str() {
+            "vertex" => {
+                map.vertexes.push(Vertex { x: block.fixed("x", None)?, y: block.fixed("y", None)? });
+                (Element::Vertex, map.vertexes.len() - 1)
+            }
+            "linedef" => {
+                let mut bits = vec![
+                    ("blocking", Linedef::BLOCKING), ("blockmonsters", Linedef::BLOCK_MONSTERS),
+                    ("twosided", Linedef::TWO_SIDED), ("dontpegtop", Linedef::DONT_PEG_TOP),
+                    ("dontpegbottom", Linedef::DONT_PEG_BOTTOM), ("secret", Linedef::SECRET),
+                    ("blocksound", Linedef::SOUND_BLOCK), ("dontdraw", Linedef::DONT_DRAW),
+                    ("mapped", Linedef::MAPPED),
+                ];
+                if zdoom {
+                    bits.extend([
+                        ("repeatspecial", Linedef::REPEAT_SPECIAL), ("monsteractivate", 0x2000),
+                        ("blockplayers", 0x4000), ("blockeverything", 0x8000),
+                    ]);
+                } else if namespace != UdmfNamespace::Doom {
+                    bits.push(("passuse", Linedef::PASS_USE));
+                }
+                let mut flags = block.flags(&bits)?;
+                if zdoom {
+                    flags |= activation(&mut block)?;
+                }
+                let line = Linedef {
+                    start_vertex: block.int("v1", None)?,
+                    end_vertex: block.int("v2", None)?,
+                    flags,
+                    special: block.int("special", Some(0))?,
+                    tag: block.int("id", Some(-1i64))?.max(0).try_into().unwrap_or(u16::MAX),
+                    front_sidedef: Some(block.int("sidefront", None)?),
+                    back_sidedef: usize::try_from(block.int("sideback", Some(-1i64))?).ok(),
+                    args: if zdoom { Some(block.args()?) } else { None },
+                };
+                map.linedefs.push(line);
+                (Element::Linedef, map.linedefs.len() - 1)
+            }
+            "sidedef" => {
+                map.sidedefs.push(Sidedef {
+                    x_offset: block.int("offsetx", Some(0))?,
+                    y_offset: block.int("offsety", Some(0))?,
+                    upper_texture: block.texture("texturetop", Some("-"))?,
+                    lower_texture: block.texture("texturebottom", Some("-"))?,
+                    middle_texture: block.texture("texturemiddle", Some("-"))?,
+                    sector: block.int("sector", None)?,
+                });
+                (Element::Sidedef, map.sidedefs.len() - 1)
+            }
+            "sector" => {
+                map.sectors.push(Sector {
+                    floor_height: block.int("heightfloor", Some(0))?,
+                    ceiling_height: block.int("heightceiling", Some(0))?,
+                    floor_texture: block.texture("texturefloor", None)?,
+                    ceiling_texture: block.texture("textureceiling", None)?,
+                    light_level: block.int("lightlevel", Some(160))?,
+                    special: block.int("special", Some(0))?,
+                    tag: block.int("id", Some(0))?,
+                });
+                (Element::Sector, map.sectors.len() - 1)
+            }
+            "thing" => {
+                let thing = thing(&mut block, namespace)?;
+                map.things.push(thing);
+                (Element::Thing, map.things.len() - 1)
+            }
+            // blocks of other ports' extensions
+            _ => continue,
+        };
+
+        if !block.fields.is_empty() {
+            map.properties.elements.insert((element, index), block.fields);
+        }
+    }
+
+    Ok(map)
+}
+
+/**
+ * The activation bits of a Hexen format linedef. Hexen lines have one way to activate their
+ * special, so the first one set wins; `passuse` turns player use into use that passes through
+ * to the lines behind, as in ZDoom.
+ */
+fn activation(block: &mut Block) -> Result<u16, MapError> {
+    const ACTIVATIONS: [&str; 6] = ["playercross", "playeruse", "monstercross", "impact", "playerpush", "missilecross"];
+    const USE: u16 = 1;
+    const USE_THROUGH: u16 = 6;
+
+    let pass_use = block.flag("passuse")?;
+    let mut activation = None;
+    for (number, key) in (0u16..).zip(ACTIVATIONS) {
+        if block.flag(key)? && activation.is_none() {
+            activation = Some(number);
+        }
+    }
+    let activation = match activation.unwrap_or(0) {
+        USE if pass_use => USE_THROUGH,
+        activation => activation,
+    };
+    Ok(activation << 10)
+}
+
+fn thing(block: &mut Block, namespace: UdmfNamespace) -> Result<Thing, MapError> {
+    let x = block.fixed("x", None)?;
+    let y = block.fixed("y", None)?;
+    let angle = block.int("angle", Some(0))?;
+    let thing_type = block.int("type", None)?;
+
+    // skill levels 1 and 2 and levels 4 and 5 share a flag in the binary formats
+    let mut flags = 0;
+    if block.flag("skill1")? | block.flag("skill2")? {
+        flags |= 0x0001;
+    }
+    if block.flag("skill3")? {
+        flags |= 0x0002;
+    }
+    if block.flag("skill4")? | block.flag("skill5")? {
+        flags |= 0x0004;
+    }
+
+    let extended = if namespace == UdmfNamespace::ZDoom {
+        flags |= block.flags(&[
+            ("ambush", 0x0008), ("dormant", 0x0010), ("class1", 0x0020), ("class2", 0x0040),
+            ("class3", 0x0080), ("single", 0x0100), ("coop", 0x0200), ("dm", 0x0400),
+        ])?;
+        Some(ExtendedThing {
+            tid: block.int("id", Some(0))?,
+            z: block.fixed("height", Some(0))?,
+            special: block.int("special", Some(0))?,
+            args: block.args()?,
+        })
+    } else {
+        flags |= block.flags(&[("ambush", 0x0008)])?;
+        if !block.flag("single")? {
+            flags |= 0x0010;
+        }
+        if matches!(namespace, UdmfNamespace::Boom | UdmfNamespace::ZDoomTranslated) {
+            if !block.flag("dm")? {
+                flags |= 0x0020;
+            }
+            if !block.flag("coop")? {
+                flags |= 0x0040;
+            }
+            flags |= block.flags(&[("friend", 0x0080)])?;
+        }
+        None
+    };
+
+    Ok(Thing { x, y, angle, thing_type, flags, extended })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(namespace: &str, fields: &str) -> Linedef {
+        let textmap = format!(r#"namespace = "{}";
+            vertex {{ x = 0.0; y = 0.0; }}
+            vertex {{ x = 64.0; y = 0.0; }}
+            sector {{ texturefloor = "FLOOR4_8"; textureceiling = "CEIL3_5"; heightceiling = 128; }}
+            sidedef {{ sector = 0; }}
+            linedef {{ v1 = 0; v2 = 1; sidefront = 0; special = 1; {} }}"#, namespace, fields);
+        parse(LumpName::new("MAP01"), textmap.as_bytes()).unwrap().linedefs[0]
+    }
+
+    #[test]
+    fn zdoomtranslated_uses_doom_flags() {
+        let translated = line("ZDoomTranslated", "passuse = true;");
+        assert_eq!(translated.flags, Linedef::PASS_USE);
+        assert_eq!(translated.args, None);
+    }
+
+    #[test]
+    fn zdoom_uses_hexen_flags() {
+        let repeatable = line("zdoom", "repeatspecial = true; playeruse = true;");
+        assert_eq!(repeatable.flags, Linedef::REPEAT_SPECIAL | 1 << 10);
+
+        let pass_use = line("zdoom", "playeruse = true; passuse = true;");
+        assert_eq!(pass_use.flags, 6 << 10);
+        assert_eq!(pass_use.args, Some([0; 5]));
+    }
+}
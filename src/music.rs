@@ -0,0 +1,162 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/**
+ * Errors while converting a MUS lump.
+ */
+#[derive(Debug)]
+pub enum MusError {
+    // the lump does not start with "MUS\x1a"
+    BadMagic,
+
+    // the score runs past the end of the lump
+    Truncated,
+}
+
+impl Display for MusError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MusError::BadMagic => write!(f, "not a MUS lump"),
+            MusError::Truncated => write!(f, "MUS score is truncated"),
+        }
+    }
+}
+
+impl Error for MusError {}
+
+const MUS_MAGIC: &[u8; 4] = b"MUS\x1a";
+
+// MUS plays at 140 ticks per second: 70 ticks per quarter note at 120 bpm
+const MIDI_DIVISION: u16 = 70;
+
+// MUS controller numbers 1 to 9 and system events 10 to 14 as MIDI controllers
+const MIDI_CONTROLLERS: [u8; 15] = [0, 0, 1, 7, 10, 11, 91, 93, 64, 67, 120, 123, 126, 127, 121];
+
+pub fn is_mus(lump: &[u8]) -> bool {
+    lump.starts_with(MUS_MAGIC)
+}
+
+/**
+ * Converts a MUS music lump (D_* lumps) into a type 0 standard MIDI file.
+ */
+pub fn mus_to_midi(lump: &[u8]) -> Result<Vec<u8>, MusError> {
+    if !is_mus(lump) {
+        return Err(MusError::BadMagic);
+    }
+    let read_u16 = |offset: usize| lump.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+        .ok_or(MusError::Truncated);
+
+    let score_len = read_u16(4)?;
+    let score_start = read_u16(6)?;
+    let score = lump.get(score_start..score_start + score_len)
+        .or_else(|| lump.get(score_start..))
+        .ok_or(MusError::Truncated)?;
+
+    let mut track: Vec<u8> = Vec::new();
+    let mut velocities = [127u8; 16];
+    let mut delay: u32 = 0;
+    let mut pos = 0;
+    let mut next = || -> Result<u8, MusError> {
+        let byte = *score.get(pos).ok_or(MusError::Truncated)?;
+        pos += 1;
+        Ok(byte)
+    };
+
+    loop {
+        let descriptor = next()?;
+        let channel = midi_channel(descriptor & 0x0f);
+        let mut event: Vec<u8> = Vec::with_capacity(3);
+
+        match (descriptor >> 4) & 0x07 {
+            // release note
+            0 => event.extend([0x80 | channel, next()? & 0x7f, 0]),
+            // play note, with an optional new velocity
+            1 => {
+                let note = next()?;
+                if note & 0x80 != 0 {
+                    velocities[channel as usize] = next()? & 0x7f;
+                }
+                event.extend([0x90 | channel, note & 0x7f, velocities[channel as usize]]);
+            }
+            // pitch bend, 8 bits in MUS and 14 bits in MIDI
+            2 => {
+                let bend = u16::from(next()?) << 6;
+                event.extend([0xe0 | channel, (bend & 0x7f) as u8, (bend >> 7) as u8]);
+            }
+            // system event
+            3 => {
+                let controller = next()? as usize;
+                if let Some(&midi) = MIDI_CONTROLLERS.get(controller).filter(|_| controller >= 10) {
+                    event.extend([0xb0 | channel, midi, 0]);
+                }
+            }
+            // change controller, controller 0 is an instrument change
+            4 => {
+                let controller = next()? as usize;
+                let value = next()?.min(127);
+                match controller {
+                    0 => event.extend([0xc0 | channel, value]),
+                    1..=9 => event.extend([0xb0 | channel, MIDI_CONTROLLERS[controller], value]),
+                    _ => {}
+                }
+            }
+            // end of measure
+            5 => {}
+            // score end
+            6 => break,
+            _ => { next()?; }
+        }
+
+        if !event.is_empty() {
+            write_var_len(&mut track, delay);
+            track.extend(event);
+            delay = 0;
+        }
+
+        if descriptor & 0x80 != 0 {
+            let mut time: u32 = 0;
+            loop {
+                let byte = next()?;
+                time = (time << 7) | u32::from(byte & 0x7f);
+                if byte & 0x80 == 0 { break; }
+            }
+            delay += time;
+        }
+    }
+
+    // end of track
+    write_var_len(&mut track, delay);
+    track.extend([0xff, 0x2f, 0x00]);
+
+    let mut midi = Vec::with_capacity(22 + track.len());
+    midi.extend_from_slice(b"MThd");
+    midi.extend_from_slice(&6u32.to_be_bytes());
+    midi.extend_from_slice(&0u16.to_be_bytes());
+    midi.extend_from_slice(&1u16.to_be_bytes());
+    midi.extend_from_slice(&MIDI_DIVISION.to_be_bytes());
+    midi.extend_from_slice(b"MTrk");
+    midi.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    midi.extend(track);
+
+    Ok(midi)
+}
+
+// MUS channel 15 is percussion, which is channel 9 in MIDI
+fn midi_channel(mus_channel: u8) -> u8 {
+    match mus_channel {
+        15 => 9,
+        9..=14 => mus_channel + 1,
+        _ => mus_channel,
+    }
+}
+
+fn write_var_len(out: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
@@ -0,0 +1,83 @@
+use miniz_oxide::deflate::compress_to_vec_zlib;
+
+const SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+// Colour type 6: red, green, blue and alpha, 8 bits each
+const COLOR_RGBA: u8 = 6;
+
+/**
+ * Encodes an image of RGBA pixels, row by row from the top, as a PNG file. PNG has no empty
+ * images, so an image without pixels gives a file that decoders reject.
+ */
+pub fn encode_rgba(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    assert_eq!(rgba.len(), width as usize * height as usize * 4, "pixel data does not match the image size");
+
+    // every row starts with its filter type, 0 for none
+    let mut raw = Vec::with_capacity(rgba.len() + height as usize);
+    for row in (0..height as usize).map(|y| &rgba[y * width as usize * 4..(y + 1) * width as usize * 4]) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend(width.to_be_bytes());
+    header.extend(height.to_be_bytes());
+    // bit depth, colour type, compression, filter and interlace method
+    header.extend([8, COLOR_RGBA, 0, 0, 0]);
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &compress_to_vec_zlib(&raw, 6));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend(crc.to_be_bytes());
+}
+
+/**
+ * The CRC-32 of the chunk type and data, as PNG (and ZIP) define it.
+ */
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+    #[test]
+    fn encodes_rows_with_filter_bytes() {
+        let png = encode_rgba(2, 1, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(&png[..8], SIGNATURE);
+        // IHDR: length, type, width, height, bit depth and colour type
+        assert_eq!(&png[8..16], b"\0\0\0\x0dIHDR");
+        assert_eq!(&png[16..26], [0, 0, 0, 2, 0, 0, 0, 1, 8, COLOR_RGBA]);
+        assert_eq!(crc32(&png[12..29]), u32::from_be_bytes(png[29..33].try_into().unwrap()));
+
+        let length = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        assert_eq!(decompress_to_vec_zlib(&png[41..41 + length]).unwrap(), [0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]));
+    }
+
+    #[test]
+    fn does_not_panic_on_empty_images() {
+        encode_rgba(0, 4, &[]);
+        encode_rgba(4, 0, &[]);
+    }
+}
@@ -0,0 +1,510 @@
+use std::collections::HashMap;
+use std::path::Path;
+use crate::game::Game;
+use crate::wad::{load_wad_file, LumpName, Wad, WadError};
+
+mod directory;
+mod zip;
+
+pub use directory::DirectoryContainer;
+pub use zip::ZipContainer;
+
+/**
+ * Lump namespaces delimited by marker lumps. Lumps inside a namespace are only looked up
+ * through that namespace, so a sprite and a flat can share a name.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Namespace {
+    // S_START/S_END, SS_START/SS_END, sprites/
+    Sprites,
+    // F_START/F_END, FF_START/FF_END, flats/
+    Flats,
+    // P_START/P_END, PP_START/PP_END, patches/
+    Patches,
+}
+
+impl Namespace {
+    const ALL: [Namespace; 3] = [Namespace::Sprites, Namespace::Flats, Namespace::Patches];
+
+    fn start_markers(self) -> [&'static str; 2] {
+        match self {
+            Namespace::Sprites => ["S_START", "SS_START"],
+            Namespace::Flats => ["F_START", "FF_START"],
+            Namespace::Patches => ["P_START", "PP_START"],
+        }
+    }
+
+    fn end_markers(self) -> [&'static str; 2] {
+        match self {
+            Namespace::Sprites => ["S_END", "SS_END"],
+            Namespace::Flats => ["F_END", "FF_END"],
+            Namespace::Patches => ["P_END", "PP_END"],
+        }
+    }
+
+    // The numbered sub-markers of the IWADs, e.g. F1_START or P2_END
+    fn is_inner_marker(self, name: &LumpName) -> bool {
+        let prefix = match self {
+            Namespace::Sprites => return false,
+            Namespace::Flats => b'F',
+            Namespace::Patches => b'P',
+        };
+        match name.as_bytes() {
+            [p, b'1'..=b'3', rest @ ..] if *p == prefix => rest == b"_START" || rest == b"_END",
+            _ => false,
+        }
+    }
+
+    /**
+     * The namespace of a folder in a PK3 or resource directory.
+     */
+    fn from_folder(folder: &str) -> Option<Namespace> {
+        match folder.to_ascii_lowercase().as_str() {
+            "sprites" => Some(Namespace::Sprites),
+            "flats" => Some(Namespace::Flats),
+            "patches" => Some(Namespace::Patches),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Where a lump of a container belongs.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Placement {
+    // found by name lookups only
+    Global,
+    // a namespace marker such as S_START, not a resource itself
+    Marker,
+    // part of a merged namespace
+    In(Namespace),
+}
+
+/**
+ * A source of named lumps: a WAD, a PK3 archive or a directory. The rest of Room goes through
+ * the `ResourceStack` and does not need to know where a lump came from.
+ */
+pub trait ResourceContainer {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lump_name(&self, index: usize) -> Option<LumpName>;
+
+    fn lump_data(&self, index: usize) -> Option<&[u8]>;
+
+    /**
+     * Placement of every lump. The default derives it from marker lumps the way WADs
+     * delimit their namespaces.
+     */
+    fn placements(&self) -> Vec<Placement> {
+        marker_placements((0..self.len()).filter_map(|index| self.lump_name(index)))
+    }
+}
+
+impl ResourceContainer for Wad {
+    fn len(&self) -> usize {
+        Wad::len(self)
+    }
+
+    fn lump_name(&self, index: usize) -> Option<LumpName> {
+        self.lump(index).map(|dir| dir.name)
+    }
+
+    fn lump_data(&self, index: usize) -> Option<&[u8]> {
+        self.lump(index).map(|dir| Wad::lump_data(self, dir))
+    }
+}
+
+/**
+ * Places lumps by the namespace markers surrounding them.
+ */
+fn marker_placements(names: impl Iterator<Item = LumpName>) -> Vec<Placement> {
+    let mut current: Option<Namespace> = None;
+
+    names.map(|name| {
+        if let Some(namespace) = Namespace::ALL.into_iter().find(|ns| ns.start_markers().iter().any(|m| name == *m)) {
+            current = Some(namespace);
+            Placement::Marker
+        } else if let Some(namespace) = Namespace::ALL.into_iter().find(|ns| ns.end_markers().iter().any(|m| name == *m)) {
+            if current == Some(namespace) {
+                current = None;
+            }
+            Placement::Marker
+        } else if current.is_some_and(|ns| ns.is_inner_marker(&name)) {
+            Placement::Marker
+        } else {
+            current.map_or(Placement::Global, Placement::In)
+        }
+    }).collect()
+}
+
+/**
+ * Opens a PWAD-like resource: a directory, a `.pk3`/`.zip` archive or a WAD file.
+ */
+pub fn open_container(path: impl AsRef<Path>) -> Result<Box<dyn ResourceContainer>, WadError> {
+    let path = path.as_ref();
+    let extension = path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase);
+
+    if path.is_dir() {
+        Ok(Box::new(DirectoryContainer::open(path)?))
+    } else if matches!(extension.as_deref(), Some("pk3" | "zip")) {
+        Ok(Box::new(ZipContainer::open(path)?))
+    } else {
+        Ok(Box::new(load_wad_file(path)?))
+    }
+}
+
+/**
+ * Lumps of an archive or directory, keyed by file path. Both are read completely when opened.
+ */
+struct PathLumps {
+    names: Vec<LumpName>,
+    data: Vec<Vec<u8>>,
+    placements: Vec<Placement>,
+}
+
+impl PathLumps {
+    /**
+     * Builds the lumps from (path, contents) pairs. Paths are sorted first so the lump order
+     * does not depend on how the archive or file system lists them.
+     */
+    fn from_files(mut files: Vec<(String, Vec<u8>)>) -> Result<PathLumps, WadError> {
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut lumps = PathLumps { names: Vec::new(), data: Vec::new(), placements: Vec::new() };
+
+        for (path, data) in files {
+            let (folder, file) = match path.split_once('/') {
+                Some((folder, file)) => (Some(folder.to_ascii_lowercase()), file),
+                None => (None, path.as_str()),
+            };
+            let file_name = file.rsplit('/').next().unwrap_or(file);
+            let stem = file_name.split_once('.').map_or(file_name, |(stem, _)| stem);
+            let name = LumpName::new(stem);
+
+            let placement = match folder.as_deref() {
+                None | Some("sounds" | "music" | "graphics" | "textures") => Placement::Global,
+                Some("maps") if file_name.to_ascii_lowercase().ends_with(".wad") => {
+                    lumps.add_map_wad(name, data)?;
+                    continue;
+                }
+                Some(folder) => match Namespace::from_folder(folder) {
+                    Some(namespace) => Placement::In(namespace),
+                    // not a lump folder, e.g. scripts or documentation
+                    None => continue,
+                },
+            };
+
+            lumps.names.push(name);
+            lumps.data.push(data);
+            lumps.placements.push(placement);
+        }
+
+        Ok(lumps)
+    }
+
+    /**
+     * A `maps/NAME.wad` holds a single map. Its lumps are added as global lumps, with the map
+     * marker renamed after the file.
+     */
+    fn add_map_wad(&mut self, map_name: LumpName, data: Vec<u8>) -> Result<(), WadError> {
+        let wad = Wad::from_bytes(data)?;
+        for (index, dir) in wad.iter().enumerate() {
+            self.names.push(if index == 0 { map_name } else { dir.name });
+            self.data.push(wad.lump_data(dir).to_vec());
+            self.placements.push(Placement::Global);
+        }
+        Ok(())
+    }
+}
+
+// DirectoryContainer and ZipContainer delegate to this
+impl ResourceContainer for PathLumps {
+    fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    fn lump_name(&self, index: usize) -> Option<LumpName> {
+        self.names.get(index).copied()
+    }
+
+    fn lump_data(&self, index: usize) -> Option<&[u8]> {
+        self.data.get(index).map(Vec::as_slice)
+    }
+
+    fn placements(&self) -> Vec<Placement> {
+        self.placements.clone()
+    }
+}
+
+// Where a lump of the stack comes from and which namespace it belongs to
+struct LumpInfo {
+    container: usize,
+    index: usize,
+    name: LumpName,
+    placement: Placement,
+}
+
+/**
+ * The lumps of one IWAD and any number of PWADs, in load order.
+ *
+ * Lumps are numbered across all containers like vanilla's lump numbers. A name lookup returns
+ * the last definition, as `W_GetNumForName` does. Sprites, flats and patches between marker
+ * lumps (or in the matching folders of a PK3) are merged per namespace instead: a PWAD adds to
+ * the IWAD's set and only replaces lumps of the same name.
+ */
+pub struct ResourceStack {
+    game: Game,
+    containers: Vec<Box<dyn ResourceContainer>>,
+    lumps: Vec<LumpInfo>,
+    names: HashMap<LumpName, usize>,
+    namespaces: HashMap<Namespace, Vec<usize>>,
+    namespace_names: HashMap<(Namespace, LumpName), usize>,
+}
+
+impl ResourceStack {
+    pub fn new(iwad: Wad) -> ResourceStack {
+        let mut stack = ResourceStack {
+            game: Game::detect(&iwad),
+            containers: Vec::new(),
+            lumps: Vec::new(),
+            names: HashMap::new(),
+            namespaces: HashMap::new(),
+            namespace_names: HashMap::new(),
+        };
+        stack.add_container(Box::new(iwad));
+        stack
+    }
+
+    /**
+     * Adds a PWAD, PK3 or directory on top of everything loaded so far. Fails for the shareware
+     * IWAD, like vanilla does.
+     */
+    pub fn add_pwad(&mut self, pwad: impl ResourceContainer + 'static) -> Result<(), WadError> {
+        self.add_boxed(Box::new(pwad))
+    }
+
+    /**
+     * Like `add_pwad`, for containers returned by `open_container`.
+     */
+    pub fn add_boxed(&mut self, pwad: Box<dyn ResourceContainer>) -> Result<(), WadError> {
+        if !self.game.allows_pwads() {
+            return Err(WadError::SharewarePwad);
+        }
+        self.add_container(pwad);
+        Ok(())
+    }
+
+    /**
+     * The game identified from the IWAD.
+     */
+    pub fn game(&self) -> Game {
+        self.game
+    }
+
+    /**
+     * Whether any PWAD has been loaded, vanilla's `modifiedgame`.
+     */
+    pub fn is_modified(&self) -> bool {
+        self.containers.len() > 1
+    }
+
+    fn add_container(&mut self, container: Box<dyn ResourceContainer>) {
+        let container_num = self.containers.len();
+
+        for (index, placement) in container.placements().into_iter().enumerate() {
+            let Some(name) = container.lump_name(index) else { continue };
+            let num = self.lumps.len();
+
+            if let Placement::In(namespace) = placement {
+                let list = self.namespaces.entry(namespace).or_default();
+                match self.namespace_names.get(&(namespace, name)) {
+                    Some(&position) => list[position] = num,
+                    None => {
+                        self.namespace_names.insert((namespace, name), list.len());
+                        list.push(num);
+                    }
+                }
+            }
+
+            self.names.insert(name, num);
+            self.lumps.push(LumpInfo { container: container_num, index, name, placement });
+        }
+
+        self.containers.push(container);
+    }
+
+    pub fn containers(&self) -> &[Box<dyn ResourceContainer>] {
+        &self.containers
+    }
+
+    /**
+     * Total number of lumps over all loaded containers.
+     */
+    pub fn len(&self) -> usize {
+        self.lumps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lumps.is_empty()
+    }
+
+    /**
+     * Number of the last lump called `name`, whatever namespace it is in.
+     */
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.names.get(&LumpName::new(name)).copied()
+    }
+
+    /**
+     * Number of the lump called `name` in the merged set of `namespace`.
+     */
+    pub fn find_in(&self, namespace: Namespace, name: &str) -> Option<usize> {
+        self.namespace_names.get(&(namespace, LumpName::new(name)))
+            .map(|&position| self.namespaces[&namespace][position])
+    }
+
+    /**
+     * Lump numbers of the merged namespace, IWAD lumps first, replaced in place by PWAD lumps
+     * of the same name and followed by the PWADs' new lumps. Marker lumps are not included.
+     */
+    pub fn namespace(&self, namespace: Namespace) -> &[usize] {
+        self.namespaces.get(&namespace).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn lump_name(&self, num: usize) -> Option<LumpName> {
+        self.lumps.get(num).map(|info| info.name)
+    }
+
+    pub fn lump_data(&self, num: usize) -> Option<&[u8]> {
+        let info = self.lumps.get(num)?;
+        self.containers[info.container].lump_data(info.index)
+    }
+
+    /**
+     * Namespace a lump was found in, `None` for global lumps and marker lumps.
+     */
+    pub fn lump_namespace(&self, num: usize) -> Option<Namespace> {
+        match self.lumps.get(num)?.placement {
+            Placement::In(namespace) => Some(namespace),
+            _ => None,
+        }
+    }
+
+    pub fn is_marker(&self, num: usize) -> bool {
+        self.lumps.get(num).is_some_and(|info| info.placement == Placement::Marker)
+    }
+
+    /**
+     * The container a lump comes from and its index in that container.
+     */
+    pub fn source(&self, num: usize) -> Option<(&dyn ResourceContainer, usize)> {
+        self.lumps.get(num).map(|info| (self.containers[info.container].as_ref(), info.index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wad::{Identification, WadWriter};
+
+    #[test]
+    fn maps_folders_to_namespaces() {
+        let mut map = WadWriter::new(Identification::PWAD);
+        map.add_marker("MAP01").add_lump("THINGS", b"things".to_vec());
+        let files = [
+            ("Sprites/trooa1.png", b"sprite".to_vec()),
+            ("FLATS/floor0_1.lmp", b"flat".to_vec()),
+            ("patches/walls/wall00_1.lmp", b"patch".to_vec()),
+            ("textures/texture1.lmp", b"textures".to_vec()),
+            ("maps/map07.wad", map.to_bytes()),
+            ("scripts/map07.acs", b"script".to_vec()),
+            ("dehacked.deh", b"patch".to_vec()),
+        ];
+        let lumps = PathLumps::from_files(files.into_iter().map(|(path, data)| (path.to_string(), data)).collect()).unwrap();
+
+        let placed: Vec<(String, Placement)> = (0..lumps.len())
+            .map(|index| (lumps.lump_name(index).unwrap().to_string(), lumps.placements()[index]))
+            .collect();
+        assert_eq!(placed, [
+            ("FLOOR0_1".to_string(), Placement::In(Namespace::Flats)),
+            ("TROOA1".to_string(), Placement::In(Namespace::Sprites)),
+            ("DEHACKED".to_string(), Placement::Global),
+            ("MAP07".to_string(), Placement::Global),
+            ("THINGS".to_string(), Placement::Global),
+            ("WALL00_1".to_string(), Placement::In(Namespace::Patches)),
+            ("TEXTURE1".to_string(), Placement::Global),
+        ]);
+    }
+
+    fn wad(identification: Identification, lumps: &[(&str, &[u8])]) -> Wad {
+        let mut writer = WadWriter::new(identification);
+        for &(name, data) in lumps {
+            writer.add_lump(name, data.to_vec());
+        }
+        Wad::from_bytes(writer.to_bytes()).unwrap()
+    }
+
+    fn data(stack: &ResourceStack, num: Option<usize>) -> &[u8] {
+        stack.lump_data(num.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn later_pwads_override_earlier_lumps() {
+        let mut stack = ResourceStack::new(wad(Identification::IWAD, &[("PLAYPAL", b"iwad"), ("COLORMAP", b"iwad")]));
+        assert!(!stack.is_modified());
+        stack.add_pwad(wad(Identification::PWAD, &[("PLAYPAL", b"first"), ("DEMO1", b"first")])).unwrap();
+        stack.add_pwad(wad(Identification::PWAD, &[("PLAYPAL", b"second")])).unwrap();
+
+        assert!(stack.is_modified());
+        assert_eq!(stack.len(), 5);
+        assert_eq!(data(&stack, stack.find("playpal")), b"second");
+        assert_eq!(data(&stack, stack.find("DEMO1")), b"first");
+        assert_eq!(data(&stack, stack.find("COLORMAP")), b"iwad");
+        assert_eq!(stack.find("ENDOOM"), None);
+        assert_eq!(stack.source(4).map(|(_, index)| index), Some(0));
+    }
+
+    #[test]
+    fn merges_namespaces_across_wads() {
+        let mut stack = ResourceStack::new(wad(Identification::IWAD, &[
+            ("S_START", b""), ("TROOA1", b"iwad"), ("S_END", b""),
+            ("F_START", b""), ("F1_START", b""), ("FLOOR0_1", b"iwad"), ("NUKAGE1", b"iwad"), ("F1_END", b""), ("F_END", b""),
+        ]));
+        stack.add_pwad(wad(Identification::PWAD, &[
+            ("SS_START", b""), ("TROOA1", b"pwad"), ("SARGA1", b"pwad"), ("SS_END", b""),
+            ("FF_START", b""), ("NUKAGE1", b"pwad"), ("FF_END", b""),
+            ("NUKAGE1", b"global"),
+        ])).unwrap();
+
+        let names = |namespace| -> Vec<String> {
+            stack.namespace(namespace).iter().map(|&num| stack.lump_name(num).unwrap().to_string()).collect()
+        };
+        // replaced lumps keep their place, new ones are added at the end
+        assert_eq!(names(Namespace::Sprites), ["TROOA1", "SARGA1"]);
+        assert_eq!(names(Namespace::Flats), ["FLOOR0_1", "NUKAGE1"]);
+        assert!(stack.namespace(Namespace::Patches).is_empty());
+
+        assert_eq!(data(&stack, stack.find_in(Namespace::Sprites, "TROOA1")), b"pwad");
+        assert_eq!(data(&stack, stack.find_in(Namespace::Flats, "NUKAGE1")), b"pwad");
+        assert_eq!(data(&stack, stack.find_in(Namespace::Flats, "FLOOR0_1")), b"iwad");
+        assert_eq!(data(&stack, stack.find("NUKAGE1")), b"global");
+        assert_eq!(stack.find_in(Namespace::Sprites, "NUKAGE1"), None);
+
+        assert!(stack.is_marker(stack.find("F1_START").unwrap()));
+        assert_eq!(stack.lump_namespace(stack.find("SARGA1").unwrap()), Some(Namespace::Sprites));
+        assert_eq!(stack.lump_namespace(stack.find("NUKAGE1").unwrap()), None);
+    }
+
+    #[test]
+    fn shareware_refuses_pwads() {
+        let mut stack = ResourceStack::new(Wad::open(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/doom1.wad")).unwrap());
+        let pwad = wad(Identification::PWAD, &[("PLAYPAL", b"pwad")]);
+        assert!(matches!(stack.add_pwad(pwad), Err(WadError::SharewarePwad)));
+        assert!(!stack.is_modified());
+        assert_eq!(stack.containers().len(), 1);
+    }
+}
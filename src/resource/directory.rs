@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use crate::resource::{PathLumps, Placement, ResourceContainer};
+use crate::wad::{LumpName, WadError};
+
+/**
+ * A directory of loose lump files, laid out like a PK3: `sprites/`, `flats/`, `patches/`,
+ * `maps/` and so on. All files are read when the directory is opened. Symbolic links are
+ * followed, but every directory is only walked once, so a link back up the tree cannot make
+ * the walk go round in circles.
+ */
+pub struct DirectoryContainer {
+    lumps: PathLumps,
+}
+
+impl DirectoryContainer {
+    pub fn open(path: impl AsRef<Path>) -> Result<DirectoryContainer, WadError> {
+        let root = path.as_ref();
+        let mut files = Vec::new();
+        let mut pending = vec![root.to_path_buf()];
+        let mut visited = HashSet::from([fs::canonicalize(root)?]);
+
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                // follows symbolic links; broken ones are skipped
+                let Ok(metadata) = fs::metadata(&path) else { continue };
+                if metadata.is_dir() {
+                    if visited.insert(fs::canonicalize(&path)?) {
+                        pending.push(path);
+                    }
+                } else if let Ok(relative) = path.strip_prefix(root) {
+                    let relative = relative.components()
+                        .map(|component| component.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/");
+                    files.push((relative, fs::read(&path)?));
+                }
+            }
+        }
+
+        Ok(DirectoryContainer { lumps: PathLumps::from_files(files)? })
+    }
+}
+
+impl ResourceContainer for DirectoryContainer {
+    fn len(&self) -> usize {
+        self.lumps.len()
+    }
+
+    fn lump_name(&self, index: usize) -> Option<LumpName> {
+        self.lumps.lump_name(index)
+    }
+
+    fn lump_data(&self, index: usize) -> Option<&[u8]> {
+        self.lumps.lump_data(index)
+    }
+
+    fn placements(&self) -> Vec<Placement> {
+        self.lumps.placements()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symbolic_links_once() {
+        use std::os::unix::fs::symlink;
+
+        let root = std::env::temp_dir().join(format!("room-directory-{}", std::process::id()));
+        let flats = root.join("flats");
+        fs::create_dir_all(&flats).unwrap();
+        fs::write(flats.join("floor0_1.lmp"), [0; 4096]).unwrap();
+        fs::write(root.join("sound.lmp"), b"sound").unwrap();
+        // a link back up the tree, one to a file and one to nowhere
+        symlink(&root, flats.join("loop")).unwrap();
+        symlink(root.join("sound.lmp"), root.join("dspistol.lmp")).unwrap();
+        symlink(root.join("missing"), root.join("broken.lmp")).unwrap();
+
+        let container = DirectoryContainer::open(&root);
+        fs::remove_dir_all(&root).unwrap();
+
+        let container = container.unwrap();
+        let names: Vec<String> = (0..container.len()).map(|index| container.lump_name(index).unwrap().to_string()).collect();
+        assert_eq!(names, ["DSPISTOL", "FLOOR0_1", "SOUND"]);
+        assert_eq!(container.lump_data(0), Some(&b"sound"[..]));
+    }
+}
@@ -0,0 +1,198 @@
+use std::fs;
+use std::path::Path;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+use crate::resource::{PathLumps, Placement, ResourceContainer};
+use crate::wad::{LumpName, WadError};
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const CENTRAL_DIRECTORY_ENTRY: u32 = 0x0201_4b50;
+const LOCAL_FILE_HEADER: u32 = 0x0403_4b50;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+/**
+ * A PK3 (ZIP) archive. Files in `sprites/`, `flats/` and `patches/` become lumps of those
+ * namespaces, files at the top level and in `sounds/`, `music/`, `graphics/` and `textures/`
+ * are global lumps named after the file without its extension, and `maps/NAME.wad` adds the
+ * map it contains. Stored and deflated entries are supported and decompressed when the
+ * archive is opened.
+ */
+pub struct ZipContainer {
+    lumps: PathLumps,
+}
+
+impl ZipContainer {
+    pub fn open(path: impl AsRef<Path>) -> Result<ZipContainer, WadError> {
+        ZipContainer::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn from_bytes(zip: &[u8]) -> Result<ZipContainer, WadError> {
+        let bad = |reason: &str| WadError::BadArchive(reason.to_string());
+        let u16_at = |offset: usize| zip.get(offset..offset + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .ok_or_else(|| bad("truncated archive"));
+        let u32_at = |offset: usize| zip.get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .ok_or_else(|| bad("truncated archive"));
+
+        // the end of central directory record is followed by a comment of up to 64 KiB
+        let search_start = zip.len().saturating_sub(22 + 0xffff);
+        let end = (search_start..zip.len().saturating_sub(21)).rev()
+            .find(|&offset| u32_at(offset).ok() == Some(END_OF_CENTRAL_DIRECTORY))
+            .ok_or_else(|| bad("no ZIP end of central directory"))?;
+
+        let entries = u16_at(end + 10)? as usize;
+        let mut entry = u32_at(end + 16)? as usize;
+        if entries == 0xffff || entry == 0xffff_ffff {
+            return Err(bad("ZIP64 archives are not supported"));
+        }
+
+        let mut files = Vec::with_capacity(entries);
+        for _ in 0..entries {
+            if u32_at(entry)? != CENTRAL_DIRECTORY_ENTRY {
+                return Err(bad("corrupt central directory"));
+            }
+            let flags = u16_at(entry + 8)?;
+            let method = u16_at(entry + 10)?;
+            let compressed_size = u32_at(entry + 20)? as usize;
+            let size = u32_at(entry + 24)? as usize;
+            let name_len = u16_at(entry + 28)? as usize;
+            let extra_len = u16_at(entry + 30)? as usize;
+            let comment_len = u16_at(entry + 32)? as usize;
+            let local_header = u32_at(entry + 42)? as usize;
+            let path = zip.get(entry + 46..entry + 46 + name_len).ok_or_else(|| bad("truncated archive"))?;
+            let path = String::from_utf8_lossy(path).replace('\\', "/");
+            entry += 46 + name_len + extra_len + comment_len;
+
+            if path.ends_with('/') {
+                continue;
+            }
+            if flags & 1 != 0 {
+                return Err(bad(&format!("{} is encrypted", path)));
+            }
+            if u32_at(local_header)? != LOCAL_FILE_HEADER {
+                return Err(bad(&format!("corrupt local header of {}", path)));
+            }
+
+            let start = local_header + 30 + u16_at(local_header + 26)? as usize + u16_at(local_header + 28)? as usize;
+            let compressed = zip.get(start..start + compressed_size)
+                .ok_or_else(|| bad(&format!("{} lies outside of the archive", path)))?;
+
+            let data = match method {
+                METHOD_STORED => compressed.to_vec(),
+                METHOD_DEFLATED => decompress_to_vec_with_limit(compressed, size)
+                    .map_err(|_| bad(&format!("{} could not be inflated", path)))?,
+                _ => return Err(bad(&format!("{} uses unsupported compression method {}", path, method))),
+            };
+            files.push((path, data));
+        }
+
+        Ok(ZipContainer { lumps: PathLumps::from_files(files)? })
+    }
+}
+
+impl ResourceContainer for ZipContainer {
+    fn len(&self) -> usize {
+        self.lumps.len()
+    }
+
+    fn lump_name(&self, index: usize) -> Option<LumpName> {
+        self.lumps.lump_name(index)
+    }
+
+    fn lump_data(&self, index: usize) -> Option<&[u8]> {
+        self.lumps.lump_data(index)
+    }
+
+    fn placements(&self) -> Vec<Placement> {
+        self.lumps.placements()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miniz_oxide::deflate::compress_to_vec;
+    use crate::resource::Namespace;
+
+    // an archive of (path, method, uncompressed contents), without checksums as they are not read
+    fn archive(files: &[(&str, u16, &[u8])]) -> Vec<u8> {
+        let mut zip = Vec::new();
+        let mut central = Vec::new();
+        for &(path, method, data) in files {
+            let compressed = if method == METHOD_DEFLATED { compress_to_vec(data, 6) } else { data.to_vec() };
+            let mut sizes = Vec::new();
+            sizes.extend(0u32.to_le_bytes());
+            sizes.extend((compressed.len() as u32).to_le_bytes());
+            sizes.extend((data.len() as u32).to_le_bytes());
+            sizes.extend((path.len() as u16).to_le_bytes());
+            sizes.extend(0u16.to_le_bytes());
+
+            central.extend(CENTRAL_DIRECTORY_ENTRY.to_le_bytes());
+            central.extend([20, 0, 20, 0, 0, 0]);
+            central.extend(method.to_le_bytes());
+            central.extend([0; 4]);
+            central.extend(&sizes);
+            central.extend([0; 10]);
+            central.extend((zip.len() as u32).to_le_bytes());
+            central.extend(path.as_bytes());
+
+            zip.extend(LOCAL_FILE_HEADER.to_le_bytes());
+            zip.extend([20, 0, 0, 0]);
+            zip.extend(method.to_le_bytes());
+            zip.extend([0; 4]);
+            zip.extend(&sizes);
+            zip.extend(path.as_bytes());
+            zip.extend(compressed);
+        }
+
+        let central_offset = zip.len() as u32;
+        zip.extend(&central);
+        zip.extend(END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        zip.extend([0; 4]);
+        zip.extend((files.len() as u16).to_le_bytes());
+        zip.extend((files.len() as u16).to_le_bytes());
+        zip.extend((central.len() as u32).to_le_bytes());
+        zip.extend(central_offset.to_le_bytes());
+        zip.extend(0u16.to_le_bytes());
+        zip
+    }
+
+    #[test]
+    fn reads_stored_and_deflated_entries() {
+        let flat = [7; 4096];
+        let zip = archive(&[
+            ("flats/", METHOD_STORED, b""),
+            ("flats/floor0_1.lmp", METHOD_DEFLATED, &flat),
+            ("sounds/dspistol.lmp", METHOD_STORED, b"sound"),
+        ]);
+        let container = ZipContainer::from_bytes(&zip).unwrap();
+
+        assert_eq!(container.len(), 2);
+        assert_eq!(container.lump_name(0), Some(LumpName::new("FLOOR0_1")));
+        assert_eq!(container.lump_data(0), Some(&flat[..]));
+        assert_eq!(container.lump_name(1), Some(LumpName::new("DSPISTOL")));
+        assert_eq!(container.lump_data(1), Some(&b"sound"[..]));
+        assert_eq!(container.placements(), [Placement::In(Namespace::Flats), Placement::Global]);
+    }
+
+    #[test]
+    fn rejects_bad_archives() {
+        let zip = archive(&[("music/d_e1m1.mus", METHOD_STORED, b"MUS\x1a")]);
+        let bad = |zip: &[u8]| matches!(ZipContainer::from_bytes(zip), Err(WadError::BadArchive(_)));
+
+        // the central directory entry follows the local header and the data
+        let central = 30 + "music/d_e1m1.mus".len() + 4;
+        let mut corrupt = zip.clone();
+        corrupt[central] = 0;
+        assert!(bad(&corrupt));
+
+        let mut unsupported = zip.clone();
+        unsupported[central + 10] = 14;
+        assert!(bad(&unsupported));
+
+        assert!(bad(&zip[..zip.len() - 22]));
+        assert!(bad(b"PWAD"));
+    }
+}
@@ -0,0 +1,64 @@
+/**
+ * A digitized sound effect in the DMX format used by the DS* lumps.
+ *
+ * The lump starts with an 8 byte header: format number 3, the sample rate and the number of
+ * samples. The samples are unsigned 8 bit mono, with 16 padding bytes at each end that the
+ * DMX library never plays.
+ */
+pub struct Sound {
+    pub sample_rate: u16,
+    pub samples: Vec<u8>,
+}
+
+const DMX_PADDING: usize = 16;
+
+impl Sound {
+    /**
+     * Decodes a DMX sound lump, `None` if the lump is not in that format.
+     */
+    pub fn from_lump(lump: &[u8]) -> Option<Sound> {
+        if lump.len() < 8 || u16::from_le_bytes([lump[0], lump[1]]) != 3 {
+            return None;
+        }
+
+        let sample_rate = u16::from_le_bytes([lump[2], lump[3]]);
+        let count = u32::from_le_bytes(lump[4..8].try_into().unwrap()) as usize;
+        let samples = lump.get(8..8 + count)?;
+
+        // DMX skips the padding, but some PWAD sounds are stored without it
+        let samples = if count > 2 * DMX_PADDING {
+            &samples[DMX_PADDING..count - DMX_PADDING]
+        } else {
+            samples
+        };
+
+        Some(Sound { sample_rate, samples: samples.to_vec() })
+    }
+
+    /**
+     * The sound as an 8 bit mono RIFF WAVE file.
+     */
+    pub fn to_wav(&self) -> Vec<u8> {
+        let data_len = self.samples.len() as u32;
+        let mut wav = Vec::with_capacity(44 + self.samples.len());
+
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVE");
+
+        wav.extend_from_slice(b"fmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+        wav.extend_from_slice(&u32::from(self.sample_rate).to_le_bytes());
+        wav.extend_from_slice(&u32::from(self.sample_rate).to_le_bytes()); // bytes per second
+        wav.extend_from_slice(&1u16.to_le_bytes()); // block align
+        wav.extend_from_slice(&8u16.to_le_bytes()); // bits per sample
+
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        wav.extend_from_slice(&self.samples);
+
+        wav
+    }
+}
@@ -1,41 +1,139 @@
+use std::error::Error;
 use std::fmt::{Display, Formatter};
-use std::fs::File;
-use std::io::Read;
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+use std::slice::Iter;
 use std::str::FromStr;
 
+mod writer;
+
+pub use writer::WadWriter;
+
 /**
  * WAD header taken from the first 12 bytes of the WAD file.
  */
-struct Header {
+pub struct Header {
     // 4 character identification, either 'IWAD' or 'PWAD'
-    identification: Identification,
+    pub identification: Identification,
 
     // integer specifying the number of lumps (files) in the WAD
-    numlumps: i32,
+    pub numlumps: i32,
 
     // integer holding a pointer to the location of the directory.
-    infotablesofs: i32,
+    pub infotablesofs: i32,
 }
 
 /**
  * The directory associates names of lumps with the data that belong to them.
  * It consists of a number of entries, each with a length of 16 bytes.
  */
-struct Directory {
+pub struct Directory {
     // An integer holding a pointer to the start of the lump's data in the file
-    filepos: i32,
+    pub filepos: i32,
 
     // An integer representing the size of the lump in bytes
-    size: i32,
+    pub size: i32,
+
+    // The lump's name, at most 8 characters
+    pub name: LumpName,
+
+    // The 8 name bytes exactly as stored, which may be lower case or hold bytes after a NUL
+    raw_name: [u8; 8],
+}
+
+impl Directory {
+    /**
+     * The name bytes exactly as stored, for writing the entry back unchanged.
+     */
+    pub fn raw_name(&self) -> &[u8; 8] {
+        &self.raw_name
+    }
+}
+
+/**
+ * A lump name as stored in the directory: 8 bytes, NUL padded. Names are trimmed at the first
+ * NUL and converted to upper case, so they compare the way vanilla's `W_CheckNumForName` does.
+ */
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LumpName([u8; 8]);
+
+impl LumpName {
+    /**
+     * Builds a lump name from a string, truncating it to 8 characters.
+     */
+    pub fn new(name: &str) -> LumpName {
+        LumpName::from_bytes(name.as_bytes())
+    }
+
+    /**
+     * Builds a lump name from raw directory bytes, stopping at the first NUL or after 8 bytes.
+     */
+    pub fn from_bytes(bytes: &[u8]) -> LumpName {
+        let mut name = [0u8; 8];
+        for (dst, src) in name.iter_mut().zip(bytes.iter().take_while(|&&b| b != 0)) {
+            *dst = src.to_ascii_uppercase();
+        }
+        LumpName(name)
+    }
+
+    /**
+     * The name without its NUL padding.
+     */
+    pub fn as_bytes(&self) -> &[u8] {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(8);
+        &self.0[..len]
+    }
+
+    /**
+     * The full 8 byte, NUL padded form written to a directory entry.
+     */
+    pub fn to_raw(&self) -> [u8; 8] {
+        self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0[0] == 0
+    }
+}
+
+impl Display for LumpName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name: String = self.as_bytes().iter().map(|&b| char::from(b)).collect();
+        f.pad(&name)
+    }
+}
+
+impl std::fmt::Debug for LumpName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LumpName(\"{}\")", self)
+    }
+}
+
+impl From<&str> for LumpName {
+    fn from(name: &str) -> Self {
+        LumpName::new(name)
+    }
+}
+
+impl PartialEq<str> for LumpName {
+    fn eq(&self, other: &str) -> bool {
+        *self == LumpName::new(other)
+    }
+}
 
-    // A string defining the lump's name
-    name: String
+impl PartialEq<&str> for LumpName {
+    fn eq(&self, other: &&str) -> bool {
+        *self == LumpName::new(other)
+    }
 }
 
 /**
  * WAD file type, either IWAD or PWAD.
  */
-enum Identification {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Identification {
     // full game
     IWAD,
     // game mod
@@ -63,49 +161,361 @@ impl Display for Identification {
     }
 }
 
-pub fn load_wad_file(filepath: &str) -> Result<(), Box<dyn std::error::Error>> {
-    const BUFFER_LEN: usize = 512;
-    let mut buffer: [u8; BUFFER_LEN] = [0u8; BUFFER_LEN];
-    let mut file: File = File::open(filepath)?;
-    let mut wad: Vec<u8> = Vec::new();
+/**
+ * Everything that can go wrong while reading a WAD file.
+ */
+#[derive(Debug)]
+pub enum WadError {
+    // the file could not be opened or read
+    Io(io::Error),
+
+    // the first four bytes are neither 'IWAD' nor 'PWAD'
+    BadMagic([u8; 4]),
+
+    // the file ends before a structure that has to be there
+    Truncated { needed: usize, len: usize },
+
+    // the directory does not lie within the file
+    DirectoryOutOfBounds { offset: i32, numlumps: i32, len: usize },
+
+    // a directory entry points outside of the file
+    LumpOutOfBounds { name: LumpName, filepos: i32, size: i32, len: usize },
+
+    // a directory entry with a negative lump size
+    NegativeSize { name: LumpName, size: i32 },
+
+    // vanilla refuses to load PWADs on top of the shareware IWAD
+    SharewarePwad,
+
+    // a PK3/ZIP archive that cannot be read
+    BadArchive(String),
+}
+
+impl Display for WadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WadError::Io(err) => write!(f, "could not read WAD file: {}", err),
+            WadError::BadMagic(magic) =>
+                write!(f, "not a valid WAD file, bad identification {:?}", String::from_utf8_lossy(magic)),
+            WadError::Truncated { needed, len } =>
+                write!(f, "WAD file truncated, needed {} bytes but file is {} bytes long", needed, len),
+            WadError::DirectoryOutOfBounds { offset, numlumps, len } =>
+                write!(f, "directory of {} lumps at offset {} lies outside of the {} byte file", numlumps, offset, len),
+            WadError::LumpOutOfBounds { name, filepos, size, len } =>
+                write!(f, "lump {} ({} bytes at offset {}) lies outside of the {} byte file", name, size, filepos, len),
+            WadError::NegativeSize { name, size } =>
+                write!(f, "lump {} has negative size {}", name, size),
+            WadError::SharewarePwad =>
+                write!(f, "You cannot -file with the shareware version. Register!"),
+            WadError::BadArchive(reason) => write!(f, "bad archive: {}", reason),
+        }
+    }
+}
+
+impl Error for WadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WadError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WadError {
+    fn from(err: io::Error) -> Self {
+        WadError::Io(err)
+    }
+}
+
+/**
+ * A loaded WAD file: its header, the directory and the raw bytes the directory points into.
+ */
+pub struct Wad {
+    header: Header,
+    directory: Vec<Directory>,
+    data: WadData,
+}
+
+/**
+ * Backing storage of a WAD, either read into memory or mapped from disk.
+ */
+enum WadData {
+    Owned(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+impl Deref for WadData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            WadData::Owned(data) => data,
+            #[cfg(feature = "mmap")]
+            WadData::Mapped(map) => map,
+        }
+    }
+}
+
+impl Wad {
+    /**
+     * Parses a WAD held in memory, e.g. one embedded with `include_bytes!` or received over the
+     * network.
+     */
+    pub fn from_bytes(data: impl Into<Vec<u8>>) -> Result<Wad, WadError> {
+        Wad::parse(WadData::Owned(data.into()))
+    }
+
+    /**
+     * Reads a WAD file completely into memory.
+     */
+    pub fn open(path: impl AsRef<Path>) -> Result<Wad, WadError> {
+        Wad::parse(WadData::Owned(fs::read(path)?))
+    }
+
+    /**
+     * Maps a WAD file into memory instead of reading it, so lumps of large WADs are only paged
+     * in when they are used. The file must not be modified while the `Wad` is alive.
+     */
+    #[cfg(feature = "mmap")]
+    pub fn open_mmap(path: impl AsRef<Path>) -> Result<Wad, WadError> {
+        let file = fs::File::open(path)?;
+        // SAFETY: the mapping is read-only; like every mmap user we rely on nobody truncating
+        // or rewriting the file underneath us.
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Wad::parse(WadData::Mapped(map))
+    }
+
+    fn parse(data: WadData) -> Result<Wad, WadError> {
+        // Read WAD header
+        let header = read_header(&data)?;
+
+        // Read WAD directory, exactly `numlumps` entries of 16 bytes
+        let directory_len = usize::try_from(header.numlumps).ok().and_then(|numlumps| numlumps.checked_mul(16));
+        let directory_range = usize::try_from(header.infotablesofs).ok()
+            .filter(|&offset| offset >= 12)
+            .zip(directory_len)
+            .and_then(|(offset, len)| Some(offset..offset.checked_add(len)?))
+            .filter(|range| range.end <= data.len())
+            .ok_or(WadError::DirectoryOutOfBounds {
+                offset: header.infotablesofs,
+                numlumps: header.numlumps,
+                len: data.len(),
+            })?;
+
+        let mut directory: Vec<Directory> = Vec::with_capacity(header.numlumps as usize);
+        for index in directory_range.step_by(16) {
+            let dir = read_directory_entry(&data, index)?;
+            check_lump_bounds(&dir, data.len())?;
+            directory.push(dir);
+        }
+
+        Ok(Wad {
+            header,
+            directory,
+            data,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn identification(&self) -> Identification {
+        self.header.identification
+    }
+
+    /**
+     * The complete WAD file.
+     */
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /**
+     * Number of lumps in the directory.
+     */
+    pub fn len(&self) -> usize {
+        self.directory.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.directory.is_empty()
+    }
+
+    pub fn lump(&self, index: usize) -> Option<&Directory> {
+        self.directory.get(index)
+    }
+
+    /**
+     * Index of the last lump called `name`. Like vanilla `W_CheckNumForName`, later lumps win
+     * over earlier ones with the same name.
+     */
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        let name = LumpName::new(name);
+        self.directory.iter().rposition(|dir| dir.name == name)
+    }
+
+    pub fn lump_by_name(&self, name: &str) -> Option<&Directory> {
+        self.index_of(name).map(|index| &self.directory[index])
+    }
+
+    pub fn iter(&self) -> Iter<'_, Directory> {
+        self.directory.iter()
+    }
+
+    /**
+     * The bytes belonging to a directory entry of this WAD.
+     */
+    pub fn lump_data(&self, dir: &Directory) -> &[u8] {
+        let start = dir.filepos as usize;
+        &self.data[start..start + dir.size as usize]
+    }
+}
+
+impl<'a> IntoIterator for &'a Wad {
+    type Item = &'a Directory;
+    type IntoIter = Iter<'a, Directory>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
 
-    loop {
-        let read_count = file.read(&mut buffer)?;
-        wad.append(&mut buffer.to_vec());
+/**
+ * Loads a WAD file, memory-mapped when the `mmap` feature is enabled.
+ */
+pub fn load_wad_file(filepath: impl AsRef<Path>) -> Result<Wad, WadError> {
+    #[cfg(feature = "mmap")]
+    return Wad::open_mmap(filepath);
+
+    #[cfg(not(feature = "mmap"))]
+    return Wad::open(filepath);
+}
 
-        if read_count != BUFFER_LEN { break; }
+fn read_header(wad: &[u8]) -> Result<Header, WadError> {
+    if wad.len() < 12 {
+        return Err(WadError::Truncated { needed: 12, len: wad.len() });
     }
 
-    // Read WAD header
-    let signature: Identification = Identification::from_str(std::str::from_utf8(&wad[..=3]).unwrap())
-        .expect("Not a valid WAD file!");
-    let num_lumps: i32 = i32::from_le_bytes(wad[4..=7].try_into().unwrap());
-    let off_fat: i32 = i32::from_le_bytes(wad[8..=11].try_into().unwrap());
+    let magic: [u8; 4] = wad[..4].try_into().unwrap();
+    let identification = std::str::from_utf8(&magic).ok()
+        .and_then(|ident| Identification::from_str(ident).ok())
+        .ok_or(WadError::BadMagic(magic))?;
 
-    // Read WAD directory
-    let mut directory: Vec<Directory> = Vec::new();
-    let mut index = off_fat;
-    while usize::try_from(index+16).unwrap() < wad.len() {
-        directory.push(read_directory_entry(&wad, usize::try_from(index).unwrap()));
-        index += 16;
+    Ok(Header {
+        identification,
+        numlumps: read_i32(wad, 4)?,
+        infotablesofs: read_i32(wad, 8)?,
+    })
+}
+
+fn read_directory_entry(wad: &[u8], index: usize) -> Result<Directory, WadError> {
+    let name = wad.get(index + 8..index + 16)
+        .ok_or(WadError::Truncated { needed: index + 16, len: wad.len() })?;
+
+    Ok(Directory {
+        filepos: read_i32(wad, index)?,
+        size: read_i32(wad, index + 4)?,
+        name: LumpName::from_bytes(name),
+        raw_name: name.try_into().unwrap(),
+    })
+}
+
+fn check_lump_bounds(dir: &Directory, len: usize) -> Result<(), WadError> {
+    if dir.size < 0 {
+        return Err(WadError::NegativeSize { name: dir.name, size: dir.size });
     }
 
-    println!("--- HEADER ---");
-    println!("WadIdent: {}", signature);
-    println!("NumLumps: {}", num_lumps);
-    println!("OffFAT:   {}", off_fat);
-    println!("--- DIRECTORY ---");
-    for dir in directory {
-        println!("filepos: {}, size: {}, name: {}", dir.filepos, dir.size, dir.name);
+    let in_bounds = usize::try_from(dir.filepos)
+        .map(|filepos| filepos + dir.size as usize <= len)
+        .unwrap_or(false);
+    if !in_bounds {
+        return Err(WadError::LumpOutOfBounds {
+            name: dir.name,
+            filepos: dir.filepos,
+            size: dir.size,
+            len,
+        });
     }
 
     Ok(())
 }
 
-fn read_directory_entry(wad: &Vec<u8>, index: usize) -> Directory {
-    Directory {
-        filepos: i32::from_le_bytes(wad[index..index+4].try_into().unwrap()),
-        size: i32::from_le_bytes(wad[index+4..index+8].try_into().unwrap()),
-        name: String::from_utf8_lossy(&wad[index+8..index+16]).to_string()
+fn read_i32(wad: &[u8], index: usize) -> Result<i32, WadError> {
+    wad.get(index..index + 4)
+        .map(|bytes| i32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(WadError::Truncated { needed: index + 4, len: wad.len() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a WAD whose header announces `numlumps` entries at `offset`, followed by `rest`
+    fn wad(magic: &[u8; 4], numlumps: i32, offset: i32, rest: &[u8]) -> Vec<u8> {
+        [&magic[..], &numlumps.to_le_bytes(), &offset.to_le_bytes(), rest].concat()
+    }
+
+    // a directory entry
+    fn entry(filepos: i32, size: i32, name: &[u8; 8]) -> Vec<u8> {
+        [&filepos.to_le_bytes()[..], &size.to_le_bytes(), name].concat()
+    }
+
+    #[test]
+    fn reads_a_valid_wad() {
+        let wad = Wad::from_bytes(wad(b"PWAD", 1, 16, &[b"data", &entry(12, 4, b"demo1\0\0\0")[..]].concat())).unwrap();
+        assert_eq!(wad.identification(), Identification::PWAD);
+        assert_eq!(wad.len(), 1);
+        assert_eq!(wad.lump_data(wad.lump(0).unwrap()), b"data");
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        assert!(matches!(Wad::from_bytes(b"IWAD\x01\0\0\0".to_vec()), Err(WadError::Truncated { needed: 12, len: 8 })));
+        assert!(matches!(Wad::from_bytes(Vec::new()), Err(WadError::Truncated { needed: 12, len: 0 })));
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        assert!(matches!(Wad::from_bytes(wad(b"JWAD", 0, 12, &[])), Err(WadError::BadMagic(magic)) if &magic == b"JWAD"));
+        assert!(matches!(Wad::from_bytes(wad(b"\xffWAD", 0, 12, &[])), Err(WadError::BadMagic(_))));
+    }
+
+    #[test]
+    fn rejects_lumps_outside_the_file() {
+        let past_end = wad(b"PWAD", 1, 12, &entry(12, 100, b"DEMO1\0\0\0"));
+        assert!(matches!(Wad::from_bytes(past_end), Err(WadError::LumpOutOfBounds { filepos: 12, size: 100, len: 28, .. })));
+
+        let before_start = wad(b"PWAD", 1, 12, &entry(-4, 4, b"DEMO1\0\0\0"));
+        assert!(matches!(Wad::from_bytes(before_start), Err(WadError::LumpOutOfBounds { filepos: -4, .. })));
+
+        let negative = wad(b"PWAD", 1, 12, &entry(12, -1, b"DEMO1\0\0\0"));
+        assert!(matches!(Wad::from_bytes(negative), Err(WadError::NegativeSize { size: -1, .. })));
+    }
+
+    #[test]
+    fn rejects_directories_outside_the_file() {
+        let past_end = wad(b"IWAD", 1, 100, &entry(12, 0, b"E1M1\0\0\0\0"));
+        assert!(matches!(Wad::from_bytes(past_end), Err(WadError::DirectoryOutOfBounds { offset: 100, numlumps: 1, len: 28 })));
+
+        let in_header = wad(b"IWAD", 1, 4, &entry(12, 0, b"E1M1\0\0\0\0"));
+        assert!(matches!(Wad::from_bytes(in_header), Err(WadError::DirectoryOutOfBounds { offset: 4, .. })));
+
+        let negative = wad(b"IWAD", -1, 12, &[]);
+        assert!(matches!(Wad::from_bytes(negative), Err(WadError::DirectoryOutOfBounds { numlumps: -1, .. })));
+    }
+
+    #[test]
+    fn reads_exactly_numlumps_entries() {
+        // two entries announced, one there
+        let short = wad(b"PWAD", 2, 12, &entry(12, 0, b"MAP01\0\0\0"));
+        assert!(matches!(Wad::from_bytes(short), Err(WadError::DirectoryOutOfBounds { numlumps: 2, len: 28, .. })));
+
+        // one entry announced, whatever follows it is not part of the directory
+        let long = wad(b"PWAD", 1, 12, &[entry(12, 0, b"MAP01\0\0\0"), entry(-1, -1, b"JUNK\0\0\0\0")].concat());
+        let long = Wad::from_bytes(long).unwrap();
+        assert_eq!(long.len(), 1);
+        assert_eq!(long.index_of("MAP01"), Some(0));
     }
 }
@@ -0,0 +1,193 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use crate::wad::{Identification, LumpName, Wad};
+
+/**
+ * Builds a WAD file from named lumps and marker lumps.
+ *
+ * Lumps are written in the order they were added, each aligned to 4 bytes and followed by the
+ * directory. A writer created with `from_wad` keeps the source WAD's layout, including any
+ * padding bytes between lumps, so writing it again reproduces the file byte for byte.
+ */
+pub struct WadWriter {
+    identification: Identification,
+    lumps: Vec<PendingLump>,
+
+    // offset of the directory when reproducing an existing layout
+    directory_offset: Option<usize>,
+
+    // bytes outside of header, lumps and directory when reproducing an existing layout
+    filler: Vec<(usize, Vec<u8>)>,
+}
+
+struct PendingLump {
+    // directory name bytes, kept as they were for lumps taken over from an existing WAD
+    name: [u8; 8],
+    data: Vec<u8>,
+
+    // file position taken over from an existing WAD
+    filepos: Option<usize>,
+}
+
+impl WadWriter {
+    pub fn new(identification: Identification) -> WadWriter {
+        WadWriter {
+            identification,
+            lumps: Vec::new(),
+            directory_offset: None,
+            filler: Vec::new(),
+        }
+    }
+
+    /**
+     * Starts from the lumps of an existing WAD, keeping their positions in the file.
+     */
+    pub fn from_wad(wad: &Wad) -> WadWriter {
+        let data: &[u8] = &wad.data;
+        let directory_offset = wad.header.infotablesofs as usize;
+
+        let mut used: Vec<(usize, usize)> = vec![(0, 12), (directory_offset, directory_offset + wad.len() * 16)];
+        used.extend(wad.iter()
+            .filter(|dir| dir.size > 0)
+            .map(|dir| (dir.filepos as usize, dir.filepos as usize + dir.size as usize)));
+        used.sort_unstable();
+
+        let mut filler = Vec::new();
+        let mut cursor = 0;
+        for (start, end) in used.into_iter().chain([(data.len(), data.len())]) {
+            if start > cursor {
+                filler.push((cursor, data[cursor..start].to_vec()));
+            }
+            cursor = cursor.max(end);
+        }
+
+        WadWriter {
+            identification: wad.identification(),
+            lumps: wad.iter()
+                .map(|dir| PendingLump {
+                    name: *dir.raw_name(),
+                    data: wad.lump_data(dir).to_vec(),
+                    filepos: Some(dir.filepos as usize),
+                })
+                .collect(),
+            directory_offset: Some(directory_offset),
+            filler,
+        }
+    }
+
+    /**
+     * Appends a lump. Names longer than 8 characters are truncated.
+     */
+    pub fn add_lump(&mut self, name: impl Into<LumpName>, data: impl Into<Vec<u8>>) -> &mut Self {
+        // the directory grows, so it can no longer stay where the source WAD had it
+        self.directory_offset = None;
+        self.lumps.push(PendingLump { name: name.into().to_raw(), data: data.into(), filepos: None });
+        self
+    }
+
+    /**
+     * Appends an empty marker lump such as `E1M1`, `S_START` or `F_END`.
+     */
+    pub fn add_marker(&mut self, name: impl Into<LumpName>) -> &mut Self {
+        self.add_lump(name, Vec::new())
+    }
+
+    pub fn len(&self) -> usize {
+        self.lumps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lumps.is_empty()
+    }
+
+    /**
+     * Lays out header, lump data and directory and returns the complete file.
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let align = |offset: usize| (offset + 3) & !3;
+
+        let mut end = self.lumps.iter()
+            .filter_map(|lump| lump.filepos.map(|filepos| filepos + lump.data.len()))
+            .chain(self.filler.iter().map(|(offset, bytes)| offset + bytes.len()))
+            .fold(12, usize::max);
+
+        let mut positions = Vec::with_capacity(self.lumps.len());
+        for lump in &self.lumps {
+            positions.push(lump.filepos.unwrap_or_else(|| {
+                let filepos = align(end);
+                end = filepos + lump.data.len();
+                filepos
+            }));
+        }
+
+        let directory_offset = self.directory_offset.unwrap_or_else(|| align(end));
+        let directory_end = directory_offset + self.lumps.len() * 16;
+        let mut image = vec![0u8; end.max(directory_end)];
+
+        for (offset, bytes) in &self.filler {
+            image[*offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+
+        image[..4].copy_from_slice(self.identification.to_string().as_bytes());
+        image[4..8].copy_from_slice(&(self.lumps.len() as i32).to_le_bytes());
+        image[8..12].copy_from_slice(&(directory_offset as i32).to_le_bytes());
+
+        for (index, (lump, &filepos)) in self.lumps.iter().zip(&positions).enumerate() {
+            image[filepos..filepos + lump.data.len()].copy_from_slice(&lump.data);
+
+            let entry = directory_offset + index * 16;
+            image[entry..entry + 4].copy_from_slice(&(filepos as i32).to_le_bytes());
+            image[entry + 4..entry + 8].copy_from_slice(&(lump.data.len() as i32).to_le_bytes());
+            image[entry + 8..entry + 16].copy_from_slice(&lump.name);
+        }
+
+        image
+    }
+
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(bytes: &[u8]) -> Vec<u8> {
+        WadWriter::from_wad(&Wad::from_bytes(bytes.to_vec()).unwrap()).to_bytes()
+    }
+
+    #[test]
+    fn round_trips_iwad() {
+        let iwad = fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/doom1.wad")).unwrap();
+        assert!(round_trip(&iwad) == iwad);
+    }
+
+    #[test]
+    fn round_trips_non_canonical_names() {
+        // one lump called "abc\0junk", a padding byte and the directory
+        let mut pwad = b"PWAD\x01\x00\x00\x00\x14\x00\x00\x00".to_vec();
+        pwad.extend(b"data\xaa\xbb\xcc\xdd");
+        pwad.extend(12i32.to_le_bytes());
+        pwad.extend(4i32.to_le_bytes());
+        pwad.extend(b"abc\0junk");
+
+        let wad = Wad::from_bytes(pwad.clone()).unwrap();
+        assert_eq!(wad.lump(0).unwrap().name, LumpName::new("ABC"));
+        assert_eq!(round_trip(&pwad), pwad);
+    }
+
+    #[test]
+    fn added_lumps_use_canonical_names() {
+        let mut writer = WadWriter::new(Identification::PWAD);
+        writer.add_lump("demo1", b"demo".to_vec());
+        let bytes = writer.to_bytes();
+        assert_eq!(&bytes[bytes.len() - 8..], b"DEMO1\0\0\0");
+    }
+}